@@ -0,0 +1,217 @@
+mod iserv;
+mod oidc;
+mod webuntis;
+
+use std::{fmt::Display, future::Future, pin::Pin, sync::Arc};
+
+use reqwest::{
+    cookie::{CookieStore, Jar},
+    Client, Url,
+};
+use tracing::info;
+
+use crate::{
+    config::{AuthConfig, CredentialsConfig, SchoolConfig},
+    error::Error,
+    jwt::Claims,
+    oauth::OAuthError,
+    secret::{Secret, SecretError, SecretRef},
+};
+
+pub type AuthFuture<'a> = Pin<Box<dyn Future<Output = Result<(), AuthError>> + Send + 'a>>;
+
+/// Logs a cookie-storing client into WebUntis. Afterwards `login` asks WebUntis for a
+/// token with the cookies the provider collected.
+pub trait AuthProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn authenticate<'a>(
+        &'a self,
+        school: &'a SchoolConfig,
+        client: &'a Client,
+        credentials: &'a Credentials,
+    ) -> AuthFuture<'a>;
+}
+
+pub fn provider(config: &AuthConfig) -> Box<dyn AuthProvider> {
+    match config {
+        AuthConfig::Iserv => Box::new(iserv::Iserv),
+        AuthConfig::Webuntis { school } => Box::new(webuntis::WebUntis {
+            school: school.clone(),
+        }),
+        AuthConfig::Oidc {
+            issuer,
+            username_field,
+            password_field,
+        } => Box::new(oidc::Oidc {
+            issuer: issuer.clone(),
+            username_field: username_field.clone(),
+            password_field: password_field.clone(),
+        }),
+    }
+}
+
+pub struct Credentials {
+    pub username: Secret,
+    pub password: Secret,
+    /// Base32 TOTP secret for providers that ask for a second factor
+    pub totp: Option<Secret>,
+}
+
+impl Credentials {
+    pub fn resolve(config: &CredentialsConfig) -> Result<Self, AuthError> {
+        Ok(Self {
+            username: config.username.resolve()?,
+            password: config.password.resolve()?,
+            totp: config.totp.as_ref().map(SecretRef::resolve).transpose()?,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    Network(reqwest::Error),
+    /// The identity provider rejected username or password
+    InvalidCredentials,
+    MissingCredentials(SecretError),
+    /// A page did not look like any step of the expected flow
+    UnexpectedPage(String),
+    /// The login would send credentials to a host other than the configured one
+    UnexpectedHost(String),
+    OAuth(OAuthError),
+    NoToken,
+    /// The provider asks for a second factor, but no TOTP secret is configured
+    TotpRequired,
+    /// The configured TOTP secret is not valid base32
+    TotpSecretInvalid,
+    /// The code was rejected and the provider went back to the login form
+    TotpRejected,
+    /// Codes of two consecutive time steps were rejected
+    TotpSecretExpired,
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::Network(e) => write!(f, "network error during login: {e}"),
+            AuthError::InvalidCredentials => write!(f, "username or password were rejected"),
+            AuthError::MissingCredentials(e) => write!(f, "credentials missing: {e}"),
+            AuthError::UnexpectedPage(url) => write!(f, "unexpected page during login: {url}"),
+            AuthError::UnexpectedHost(url) => {
+                write!(
+                    f,
+                    "login redirected to {url}, which is not the configured host"
+                )
+            }
+            AuthError::OAuth(e) => e.fmt(f),
+            AuthError::NoToken => write!(f, "logged in, but WebUntis handed out no token"),
+            AuthError::TotpRequired => {
+                write!(f, "a second factor is required, but no TOTP secret is configured")
+            }
+            AuthError::TotpSecretInvalid => write!(f, "the TOTP secret is not valid base32"),
+            AuthError::TotpRejected => write!(f, "the TOTP code was rejected"),
+            AuthError::TotpSecretExpired => write!(
+                f,
+                "TOTP codes of two consecutive time steps were rejected, the secret was probably reset"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+impl AuthError {
+    /// Whether the identity provider rejected what we sent, retrying the same
+    /// credentials can lock the account
+    pub fn is_credential_failure(&self) -> bool {
+        matches!(
+            self,
+            AuthError::InvalidCredentials | AuthError::TotpRejected | AuthError::TotpSecretExpired
+        )
+    }
+}
+
+impl From<reqwest::Error> for AuthError {
+    fn from(e: reqwest::Error) -> Self {
+        AuthError::Network(e)
+    }
+}
+
+impl From<SecretError> for AuthError {
+    fn from(e: SecretError) -> Self {
+        AuthError::MissingCredentials(e)
+    }
+}
+
+impl From<OAuthError> for AuthError {
+    fn from(e: OAuthError) -> Self {
+        AuthError::OAuth(e)
+    }
+}
+
+/// Full login with `credentials`, returns the token and the cookies of the new session
+pub async fn login(
+    school: &SchoolConfig,
+    provider: &dyn AuthProvider,
+    credentials: &Credentials,
+) -> Result<(String, String), AuthError> {
+    info!(
+        "Creating new session and loggin in through {}",
+        provider.name()
+    );
+    let cookie_jar = Arc::new(Jar::default());
+    let client = reqwest::Client::builder()
+        .cookie_store(true)
+        .cookie_provider(cookie_jar.clone())
+        .build()?;
+    provider.authenticate(school, &client, credentials).await?;
+
+    let res = client
+        .get(school.untis("/WebUntis/api/token/new"))
+        .send()
+        .await?;
+    let token = res.text().await?;
+    if Claims::decode(&token).is_none() {
+        return Err(AuthError::NoToken);
+    }
+    let url = Url::parse(&school.untis("/WebUntis")).map_err(|_| AuthError::NoToken)?;
+    let needed_cookies = cookie_jar.cookies(&url).ok_or(AuthError::NoToken)?;
+    let mut untis_cookies = school.cookies();
+    untis_cookies.push_str(needed_cookies.to_str().map_err(|_| AuthError::NoToken)?);
+
+    Ok((token, untis_cookies))
+}
+
+/// Asks for a new token with the cookies of an existing session
+pub async fn try_refresh(
+    school: &SchoolConfig,
+    cookies: String,
+) -> Result<(String, String), Error> {
+    let client = reqwest::Client::builder().build()?;
+    let res = client
+        .get(school.untis("/WebUntis/api/token/new"))
+        .header("Cookie", &cookies)
+        .send()
+        .await?;
+    if let Some(e) = Error::from_response(&res) {
+        return Err(e);
+    }
+    let token = res.text().await?;
+    match Claims::decode(&token) {
+        Some(claims) if !claims.is_expired() => Ok((token, cookies)),
+        Some(_) => Err(Error::Token(
+            "the refreshed token is already expired".to_owned(),
+        )),
+        None => Err(Error::Token("the response is not a token".to_owned())),
+    }
+}
+
+/// Makes sure credentials are only ever posted to the configured identity provider
+fn check_host(url: &Url, expected: &str) -> Result<(), AuthError> {
+    let expected = Url::parse(expected).ok();
+    if expected.as_ref().and_then(Url::host_str) == url.host_str() {
+        Ok(())
+    } else {
+        Err(AuthError::UnexpectedHost(url.to_string()))
+    }
+}
@@ -0,0 +1,345 @@
+use reqwest::{Client, Url};
+use tracing::{info, warn};
+
+use super::{check_host, AuthError, AuthFuture, AuthProvider, Credentials};
+use crate::{
+    config::SchoolConfig,
+    oauth::{self, find_form, Form},
+    totp,
+};
+
+/// Login through the school's IServ, which acts as OAuth server for WebUntis
+pub struct Iserv;
+
+impl AuthProvider for Iserv {
+    fn name(&self) -> &'static str {
+        "iserv"
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        school: &'a SchoolConfig,
+        client: &'a Client,
+        credentials: &'a Credentials,
+    ) -> AuthFuture<'a> {
+        Box::pin(async move {
+            let res = client
+                .get(school.untis("/WebUntis/oidc/login"))
+                .header("Cookie", school.cookies())
+                .send()
+                .await?;
+            let redirect_url = res.url().clone();
+            let res = client.get(redirect_url).send().await?;
+            let login_url = res.url().clone();
+            if let Some(iserv) = &school.iserv_url {
+                check_host(&login_url, iserv)?;
+            }
+            let params = [
+                ("_username", credentials.username.expose()),
+                ("_password", credentials.password.expose()),
+            ];
+            let res = client.post(login_url).form(&params).send().await?;
+            let mut url = res.url().clone();
+            let mut text = res.text().await?;
+            if oauth::meta_refresh(&text).is_none() && totp_form(&text).is_some() {
+                (url, text) = second_factor(client, url, text, credentials).await?;
+            }
+            let Some(redirect) = oauth::meta_refresh(&text) else {
+                return Err(if has_password_form(&text) {
+                    AuthError::InvalidCredentials
+                } else {
+                    AuthError::UnexpectedPage(url.to_string())
+                });
+            };
+            let res = client.get(redirect).send().await?;
+            let url = res.url().to_string();
+            let form = oauth::parse_authorize_form(&url, &res.text().await?, Some(&school.oauth))?;
+            client.post(form.action).form(&form.params).send().await?;
+            Ok(())
+        })
+    }
+}
+
+/// Answers the two factor challenge with a code generated from the configured secret.
+/// A rejected code is retried once in the next time step in case the clocks differ,
+/// if that one fails as well the secret is most likely no longer valid.
+async fn second_factor(
+    client: &Client,
+    mut url: Url,
+    mut text: String,
+    credentials: &Credentials,
+) -> Result<(Url, String), AuthError> {
+    let secret = credentials.totp.as_ref().ok_or(AuthError::TotpRequired)?;
+    info!("IServ asks for a second factor");
+    for attempt in 0..2 {
+        let Some((form, field)) = totp_form(&text) else {
+            break;
+        };
+        if attempt > 0 {
+            warn!("TOTP code was rejected, retrying with the next one");
+            tokio::time::sleep(std::time::Duration::from_secs(totp::until_next_step())).await;
+        }
+        let code = totp::now(secret.expose()).ok_or(AuthError::TotpSecretInvalid)?;
+        let action = match &form.action {
+            Some(a) => url
+                .join(a)
+                .map_err(|_| AuthError::UnexpectedPage(a.clone()))?,
+            None => url.clone(),
+        };
+        check_host(&action, url.as_str())?;
+        let mut params = form.fields;
+        params.push((field, code));
+        let res = client.post(action).form(&params).send().await?;
+        url = res.url().clone();
+        text = res.text().await?;
+        if oauth::meta_refresh(&text).is_some() {
+            return Ok((url, text));
+        }
+        if totp_form(&text).is_none() {
+            return Err(if has_password_form(&text) {
+                AuthError::TotpRejected
+            } else {
+                AuthError::UnexpectedPage(url.to_string())
+            });
+        }
+    }
+    Err(AuthError::TotpSecretExpired)
+}
+
+/// Finds a form asking for a one time code and returns it with the name of the code field
+fn totp_form(html: &str) -> Option<(Form, String)> {
+    let is_code = |name: &str, kind: &str| {
+        let name = name.to_ascii_lowercase();
+        matches!(kind, "text" | "number" | "tel")
+            && ["totp", "otp", "code", "2fa"]
+                .iter()
+                .any(|k| name.contains(k))
+    };
+    let form = find_form(html, |_, inputs| {
+        inputs.iter().any(|i| {
+            let kind = i.attr("type").unwrap_or("text").to_ascii_lowercase();
+            i.attr("name").is_some_and(|n| is_code(n, &kind))
+        })
+    })?;
+    if form.has_input("password") {
+        return None;
+    }
+    let field = form
+        .inputs
+        .iter()
+        .find(|i| is_code(&i.name, &i.kind))?
+        .name
+        .clone();
+    Some((form, field))
+}
+
+fn has_password_form(html: &str) -> bool {
+    find_form(html, |_, _| true).is_some_and(|f| f.has_input("password"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{SystemTime, UNIX_EPOCH},
+    };
+
+    use bytes::Bytes;
+    use hyper::{header::SET_COOKIE, Method, Request, Response, StatusCode};
+
+    use super::*;
+    use crate::{
+        auth::login,
+        jwt::Claims,
+        testutil::{cookie, form, port, redirect, school, secret, serve, status, webuntis},
+    };
+
+    const LOGIN_PAGE: &str = r#"<form method="post"><input type="text" name="_username"><input type="password" name="_password"></form>"#;
+
+    const CONSENT_PAGE: &str = r#"<form name="iserv_oauth_server_authorize_form" method="post" action="/iserv/oauth/v2/auth/consent">
+        <input type="hidden" name="iserv_oauth_server_authorize_form[_token]" value="csrf">
+        <button type="submit" name="accepted">Zulassen</button></form>"#;
+
+    const TOTP_PAGE: &str = r#"<form method="post" action="/iserv/auth/2fa"><input type="hidden" name="_csrf" value="t"><input type="text" name="_totp" autocomplete="one-time-code"></form>"#;
+
+    const TOTP_SECRET: &str = "GEZDGNBVGY3TQOJQ";
+
+    /// IServ on `idp` and WebUntis on 127.0.0.1, both served by the same mock. With
+    /// `second_factor` the login asks for a code of `TOTP_SECRET` and answers a wrong
+    /// one with the given page.
+    fn iserv(req: Request<Bytes>, idp: &str, second_factor: Option<&str>) -> Response<String> {
+        if let Some(res) = webuntis(&req) {
+            return res;
+        }
+        let port = port(&req);
+        let authorize = format!(
+            "http://{idp}:{port}/iserv/oauth/v2/auth?client_id=untis&redirect_uri=untis&state=st%3D"
+        );
+        match (req.method(), req.uri().path()) {
+            (&Method::GET, "/WebUntis/oidc/login") => redirect(&authorize),
+            (&Method::GET, "/iserv/oauth/v2/auth")
+                if cookie(&req, "IServSession") == Some("ok") =>
+            {
+                Response::new(CONSENT_PAGE.to_owned())
+            }
+            (&Method::GET, "/iserv/oauth/v2/auth") => redirect("/iserv/auth/login"),
+            (&Method::GET, "/iserv/auth/login") => Response::new(LOGIN_PAGE.to_owned()),
+            (&Method::POST, "/iserv/auth/login") => {
+                let form = form(&req);
+                if form["_username"] != "anna" || form["_password"] != "geheim" {
+                    return Response::new(LOGIN_PAGE.to_owned());
+                }
+                if second_factor.is_some() {
+                    return Response::new(TOTP_PAGE.to_owned());
+                }
+                Response::builder()
+                    .header(SET_COOKIE, "IServSession=ok; Path=/")
+                    .body(format!(
+                        r#"<meta http-equiv="refresh" content="0; url={authorize}">"#
+                    ))
+                    .unwrap()
+            }
+            (&Method::POST, "/iserv/auth/2fa") => {
+                let form = form(&req);
+                let step = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs()
+                    / totp::STEP;
+                // the previous step is accepted as well in case the test crosses a step
+                let valid = [step, step - 1]
+                    .map(|step| totp::code(TOTP_SECRET, step))
+                    .contains(&form.get("_totp").cloned());
+                if form["_csrf"] != "t" || !valid {
+                    return Response::new(second_factor.unwrap_or_default().to_owned());
+                }
+                Response::builder()
+                    .header(SET_COOKIE, "IServSession=ok; Path=/")
+                    .body(format!(
+                        r#"<meta http-equiv="refresh" content="0; url={authorize}">"#
+                    ))
+                    .unwrap()
+            }
+            (&Method::POST, "/iserv/oauth/v2/auth/consent") => {
+                let form = form(&req);
+                let field = |name: &str| form.get(&oauth::field_name(name)).map(String::as_str);
+                if field("_token") != Some("csrf") || field("state") != Some("st=") {
+                    return status(StatusCode::BAD_REQUEST);
+                }
+                redirect(&format!(
+                    "http://127.0.0.1:{port}/WebUntis/oidc/callback?code=c"
+                ))
+            }
+            _ => status(StatusCode::NOT_FOUND),
+        }
+    }
+
+    fn credentials(password: &str) -> Credentials {
+        Credentials {
+            username: secret("anna"),
+            password: secret(password),
+            totp: None,
+        }
+    }
+
+    fn with_totp(secret_value: &str) -> Credentials {
+        Credentials {
+            totp: Some(secret(secret_value)),
+            ..credentials("geheim")
+        }
+    }
+
+    fn config(port: u16) -> crate::config::SchoolConfig {
+        school(
+            &format!("http://127.0.0.1:{port}"),
+            &format!(r#"iserv_url = "http://localhost:{port}""#),
+        )
+    }
+
+    #[tokio::test]
+    async fn logs_in() {
+        let addr = serve(|req| iserv(req, "localhost", None)).await;
+        let (token, cookies) = login(&config(addr.port()), &Iserv, &credentials("geheim"))
+            .await
+            .unwrap();
+        assert!(Claims::decode(&token).is_some());
+        assert!(cookies.contains("JSESSIONID=untis"));
+    }
+
+    #[tokio::test]
+    async fn rejected_password() {
+        let addr = serve(|req| iserv(req, "localhost", None)).await;
+        let err = login(&config(addr.port()), &Iserv, &credentials("falsch"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AuthError::InvalidCredentials), "{err}");
+    }
+
+    #[tokio::test]
+    async fn refuses_foreign_host() {
+        let posted = Arc::new(AtomicBool::new(false));
+        let seen = posted.clone();
+        let addr = serve(move |req| {
+            if req.method() == Method::POST {
+                seen.store(true, Ordering::Relaxed);
+            }
+            iserv(req, "127.0.0.1", None)
+        })
+        .await;
+        let err = login(&config(addr.port()), &Iserv, &credentials("geheim"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AuthError::UnexpectedHost(_)), "{err}");
+        assert!(!posted.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn answers_second_factor() {
+        let addr = serve(|req| iserv(req, "localhost", Some(LOGIN_PAGE))).await;
+        let (_, cookies) = login(&config(addr.port()), &Iserv, &with_totp(TOTP_SECRET))
+            .await
+            .unwrap();
+        assert!(cookies.contains("JSESSIONID=untis"));
+    }
+
+    #[tokio::test]
+    async fn second_factor_without_secret() {
+        let addr = serve(|req| iserv(req, "localhost", Some(LOGIN_PAGE))).await;
+        let err = login(&config(addr.port()), &Iserv, &credentials("geheim"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AuthError::TotpRequired), "{err}");
+    }
+
+    #[tokio::test]
+    async fn rejected_code() {
+        // IServ goes back to the login form after a wrong code
+        let addr = serve(|req| iserv(req, "localhost", Some(LOGIN_PAGE))).await;
+        let err = login(&config(addr.port()), &Iserv, &with_totp("JBSWY3DPEHPK3PXP"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AuthError::TotpRejected), "{err}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_secret() {
+        // IServ asks again after a wrong code, after two codes the secret is given up on
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counted = attempts.clone();
+        let addr = serve(move |req| {
+            if req.uri().path() == "/iserv/auth/2fa" {
+                counted.fetch_add(1, Ordering::Relaxed);
+            }
+            iserv(req, "localhost", Some(TOTP_PAGE))
+        })
+        .await;
+        let err = login(&config(addr.port()), &Iserv, &with_totp("JBSWY3DPEHPK3PXP"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AuthError::TotpSecretExpired), "{err}");
+        assert_eq!(attempts.load(Ordering::Relaxed), 2);
+    }
+}
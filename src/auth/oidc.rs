@@ -0,0 +1,188 @@
+use reqwest::{Client, Url};
+
+use super::{check_host, AuthError, AuthFuture, AuthProvider, Credentials};
+use crate::{
+    config::SchoolConfig,
+    oauth::{find_form, Form},
+};
+
+/// WebUntis OIDC login against an identity provider with a plain login form,
+/// e.g. Keycloak
+pub struct Oidc {
+    pub issuer: String,
+    pub username_field: Option<String>,
+    pub password_field: Option<String>,
+}
+
+impl Oidc {
+    fn username_field(&self, form: &Form) -> Option<String> {
+        self.username_field.clone().or_else(|| {
+            form.inputs
+                .iter()
+                .find(|i| matches!(i.kind.as_str(), "text" | "email"))
+                .map(|i| i.name.clone())
+        })
+    }
+
+    fn password_field(&self, form: &Form) -> Option<String> {
+        self.password_field.clone().or_else(|| {
+            form.inputs
+                .iter()
+                .find(|i| i.kind == "password")
+                .map(|i| i.name.clone())
+        })
+    }
+}
+
+impl AuthProvider for Oidc {
+    fn name(&self) -> &'static str {
+        "oidc"
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        school: &'a SchoolConfig,
+        client: &'a Client,
+        credentials: &'a Credentials,
+    ) -> AuthFuture<'a> {
+        Box::pin(async move {
+            let res = client
+                .get(school.untis("/WebUntis/oidc/login"))
+                .header("Cookie", school.cookies())
+                .send()
+                .await?;
+            let login_url = res.url().clone();
+            check_host(&login_url, &self.issuer)?;
+            let html = res.text().await?;
+            let form = find_form(&html, |_, inputs| {
+                inputs.iter().any(|i| {
+                    i.attr("type")
+                        .is_some_and(|t| t.eq_ignore_ascii_case("password"))
+                })
+            })
+            .ok_or(AuthError::UnexpectedPage(login_url.to_string()))?;
+            let (Some(username), Some(password)) =
+                (self.username_field(&form), self.password_field(&form))
+            else {
+                return Err(AuthError::UnexpectedPage(login_url.to_string()));
+            };
+            let action = match &form.action {
+                Some(a) => login_url
+                    .join(a)
+                    .map_err(|_| AuthError::UnexpectedPage(a.clone()))?,
+                None => login_url.clone(),
+            };
+            check_host(&action, &self.issuer)?;
+            let mut params = form.fields;
+            params.push((username, credentials.username.expose().to_owned()));
+            params.push((password, credentials.password.expose().to_owned()));
+
+            // the identity provider redirects back to the WebUntis callback on success
+            let res = client.post(action).form(&params).send().await?;
+            let untis = Url::parse(&school.untis("/")).ok();
+            if res.url().host_str() == untis.as_ref().and_then(Url::host_str) {
+                return Ok(());
+            }
+            let url = res.url().to_string();
+            let html = res.text().await?;
+            if find_form(&html, |_, _| true).is_some_and(|f| f.has_input("password")) {
+                Err(AuthError::InvalidCredentials)
+            } else {
+                Err(AuthError::UnexpectedPage(url))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use hyper::{Method, Request, Response, StatusCode};
+
+    use super::*;
+    use crate::{
+        auth::login,
+        testutil::{form, port, redirect, school, secret, serve, status, webuntis},
+    };
+
+    const LOGIN_PAGE: &str = r#"<form id="kc-form-login" action="/realms/school/login-actions/authenticate?session_code=x" method="post">
+        <input type="hidden" name="execution" value="e1">
+        <input type="text" name="username"><input type="password" name="password">
+        <input type="submit" value="Anmelden"></form>"#;
+
+    /// Identity provider on `idp` and WebUntis on 127.0.0.1, both served by the same mock
+    fn keycloak(req: Request<Bytes>, idp: &str) -> Response<String> {
+        if let Some(res) = webuntis(&req) {
+            return res;
+        }
+        let port = port(&req);
+        match (req.method(), req.uri().path()) {
+            (&Method::GET, "/WebUntis/oidc/login") => redirect(&format!(
+                "http://{idp}:{port}/realms/school/protocol/openid-connect/auth?client_id=untis"
+            )),
+            (&Method::GET, "/realms/school/protocol/openid-connect/auth") => {
+                Response::new(LOGIN_PAGE.to_owned())
+            }
+            (&Method::POST, "/realms/school/login-actions/authenticate") => {
+                let form = form(&req);
+                if form["execution"] != "e1"
+                    || form["username"] != "anna"
+                    || form["password"] != "geheim"
+                {
+                    return Response::new(LOGIN_PAGE.to_owned());
+                }
+                redirect(&format!(
+                    "http://127.0.0.1:{port}/WebUntis/oidc/callback?code=c"
+                ))
+            }
+            _ => status(StatusCode::NOT_FOUND),
+        }
+    }
+
+    fn provider(port: u16) -> Oidc {
+        Oidc {
+            issuer: format!("http://localhost:{port}/realms/school"),
+            username_field: None,
+            password_field: None,
+        }
+    }
+
+    fn credentials(password: &str) -> Credentials {
+        Credentials {
+            username: secret("anna"),
+            password: secret(password),
+            totp: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn logs_in() {
+        let addr = serve(|req| keycloak(req, "localhost")).await;
+        let school = school(&format!("http://127.0.0.1:{}", addr.port()), "");
+        let (_, cookies) = login(&school, &provider(addr.port()), &credentials("geheim"))
+            .await
+            .unwrap();
+        assert!(cookies.contains("JSESSIONID=untis"));
+    }
+
+    #[tokio::test]
+    async fn rejected_password() {
+        let addr = serve(|req| keycloak(req, "localhost")).await;
+        let school = school(&format!("http://127.0.0.1:{}", addr.port()), "");
+        let err = login(&school, &provider(addr.port()), &credentials("falsch"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AuthError::InvalidCredentials), "{err}");
+    }
+
+    #[tokio::test]
+    async fn refuses_foreign_host() {
+        // WebUntis redirects to 127.0.0.1, but only localhost is the configured issuer
+        let addr = serve(|req| keycloak(req, "127.0.0.1")).await;
+        let school = school(&format!("http://127.0.0.1:{}", addr.port()), "");
+        let err = login(&school, &provider(addr.port()), &credentials("geheim"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AuthError::UnexpectedHost(_)), "{err}");
+    }
+}
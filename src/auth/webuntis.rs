@@ -0,0 +1,153 @@
+use base64::{engine::general_purpose::STANDARD_PAD_INDIFFERENT, Engine};
+use reqwest::Client;
+use serde_json::Value;
+
+use super::{check_host, AuthError, AuthFuture, AuthProvider, Credentials};
+use crate::config::SchoolConfig;
+
+/// Username and password login against WebUntis itself
+pub struct WebUntis {
+    /// Login name of the school, derived from the `schoolname` cookie if unset
+    pub school: Option<String>,
+}
+
+impl WebUntis {
+    fn school_name(&self, school: &SchoolConfig) -> String {
+        if let Some(name) = &self.school {
+            return name.clone();
+        }
+        // the cookie holds the login name as `_` followed by its base64 encoding
+        school
+            .school_name
+            .strip_prefix('_')
+            .and_then(|encoded| STANDARD_PAD_INDIFFERENT.decode(encoded).ok())
+            .and_then(|bytes| String::from_utf8(bytes).ok())
+            .unwrap_or(school.school_name.clone())
+    }
+}
+
+impl AuthProvider for WebUntis {
+    fn name(&self) -> &'static str {
+        "webuntis"
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        school: &'a SchoolConfig,
+        client: &'a Client,
+        credentials: &'a Credentials,
+    ) -> AuthFuture<'a> {
+        Box::pin(async move {
+            let school_name = self.school_name(school);
+            let params = [
+                ("school", school_name.as_str()),
+                ("j_username", credentials.username.expose()),
+                ("j_password", credentials.password.expose()),
+                ("token", ""),
+            ];
+            let res = client
+                .post(school.untis("/WebUntis/j_spring_security_check"))
+                .header("Cookie", school.cookies())
+                .header("Accept", "application/json")
+                .form(&params)
+                .send()
+                .await?;
+            // the session cookie of a redirect to another host is of no use for WebUntis
+            check_host(res.url(), &school.untis_url)?;
+            let url = res.url().to_string();
+            let body = res
+                .json::<Value>()
+                .await
+                .map_err(|_| AuthError::UnexpectedPage(url.clone()))?;
+            if body.get("state").and_then(Value::as_str) == Some("SUCCESS") {
+                Ok(())
+            } else if body.get("loginError").is_some() || body.get("loginFailed").is_some() {
+                Err(AuthError::InvalidCredentials)
+            } else {
+                Err(AuthError::UnexpectedPage(url))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use hyper::{header::SET_COOKIE, Method, Request, Response, StatusCode};
+
+    use super::*;
+    use crate::{
+        auth::login,
+        testutil::{cookie, form, port, redirect, school, secret, serve, status, token},
+    };
+
+    fn untis(req: Request<Bytes>) -> Response<String> {
+        match (req.method(), req.uri().path()) {
+            (&Method::POST, "/WebUntis/j_spring_security_check") => {
+                let form = form(&req);
+                if form["school"] != "test" || form["j_username"] != "anna" {
+                    return Response::new(r#"{"loginFailed":true}"#.to_owned());
+                }
+                match form["j_password"].as_str() {
+                    "geheim" => Response::builder()
+                        .header(SET_COOKIE, "JSESSIONID=untis; Path=/WebUntis")
+                        .body(r#"{"state":"SUCCESS"}"#.to_owned())
+                        .unwrap(),
+                    "weiter" => redirect(&format!(
+                        "http://localhost:{}/WebUntis/j_spring_security_check",
+                        port(&req)
+                    )),
+                    _ => Response::new(r#"{"loginError":"invalid"}"#.to_owned()),
+                }
+            }
+            (&Method::GET, "/WebUntis/api/token/new")
+                if cookie(&req, "JSESSIONID") == Some("untis") =>
+            {
+                Response::new(token())
+            }
+            _ => status(StatusCode::NOT_FOUND),
+        }
+    }
+
+    fn credentials(password: &str) -> Credentials {
+        Credentials {
+            username: secret("anna"),
+            password: secret(password),
+            totp: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn logs_in() {
+        let addr = serve(untis).await;
+        let school = school(&format!("http://127.0.0.1:{}", addr.port()), "");
+        // the login name is decoded from the schoolname cookie
+        let provider = WebUntis { school: None };
+        let (_, cookies) = login(&school, &provider, &credentials("geheim"))
+            .await
+            .unwrap();
+        assert!(cookies.contains("JSESSIONID=untis"));
+    }
+
+    #[tokio::test]
+    async fn rejected_password() {
+        let addr = serve(untis).await;
+        let school = school(&format!("http://127.0.0.1:{}", addr.port()), "");
+        let provider = WebUntis { school: None };
+        let err = login(&school, &provider, &credentials("falsch"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AuthError::InvalidCredentials), "{err}");
+    }
+
+    #[tokio::test]
+    async fn refuses_foreign_host() {
+        let addr = serve(untis).await;
+        let school = school(&format!("http://127.0.0.1:{}", addr.port()), "");
+        let provider = WebUntis { school: None };
+        let err = login(&school, &provider, &credentials("weiter"))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, AuthError::UnexpectedHost(_)), "{err}");
+    }
+}
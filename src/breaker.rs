@@ -0,0 +1,343 @@
+use std::{
+    hash::{BuildHasher, RandomState},
+    sync::Mutex,
+    time::Duration,
+};
+
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::{config::BreakerConfig, error::Error, fetch::unix_now};
+
+/// Seconds after which another probe is let through if the first did not report back
+const PROBE_TIMEOUT: u64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    Closed,
+    /// No requests until the backoff is over
+    Open,
+    /// One probe request is let through, its outcome closes or reopens the breaker
+    HalfOpen,
+}
+
+#[derive(Serialize)]
+pub struct BreakerStatus {
+    pub state: BreakerState,
+    pub consecutive_failures: u32,
+    /// Unix timestamp since which the breaker has not been closed
+    pub open_since: Option<u64>,
+    /// Unix timestamp at which the next probe is let through
+    pub retry_at: Option<u64>,
+}
+
+struct Inner {
+    state: BreakerState,
+    failures: u32,
+    /// How often the breaker opened in a row, each time the backoff doubles
+    trips: u32,
+    open_since: Option<u64>,
+    retry_at: u64,
+    probing: bool,
+}
+
+/// Circuit breaker shared by all fetch tasks of a tenant. After `failure_threshold`
+/// failed requests in a row, or as soon as upstream answers 429, requests are refused
+/// until the backoff is over. Then a single probe decides whether it closes again.
+pub struct Breaker {
+    config: BreakerConfig,
+    inner: Mutex<Inner>,
+}
+
+impl Breaker {
+    pub fn new(config: BreakerConfig) -> Self {
+        Self {
+            config,
+            inner: Mutex::new(Inner {
+                state: BreakerState::Closed,
+                failures: 0,
+                trips: 0,
+                open_since: None,
+                retry_at: 0,
+                probing: false,
+            }),
+        }
+    }
+
+    /// Whether a request may be sent now. An error carries the time of the next probe.
+    pub fn acquire(&self) -> Result<(), Error> {
+        let mut inner = self.inner.lock().unwrap();
+        match inner.state {
+            BreakerState::Closed => Ok(()),
+            BreakerState::Open if unix_now() >= inner.retry_at => {
+                info!("Circuit breaker half open, probing upstream");
+                inner.state = BreakerState::HalfOpen;
+                inner.probing = true;
+                inner.retry_at = unix_now() + PROBE_TIMEOUT;
+                Ok(())
+            }
+            // a probe that never reported back must not keep the breaker half open
+            BreakerState::HalfOpen if !inner.probing || unix_now() >= inner.retry_at => {
+                inner.probing = true;
+                inner.retry_at = unix_now() + PROBE_TIMEOUT;
+                Ok(())
+            }
+            _ => Err(Error::CircuitOpen(inner.retry_at)),
+        }
+    }
+
+    pub fn success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.state != BreakerState::Closed {
+            info!("Circuit breaker closed, upstream answers again");
+        }
+        inner.state = BreakerState::Closed;
+        inner.failures = 0;
+        inner.trips = 0;
+        inner.open_since = None;
+        inner.probing = false;
+    }
+
+    /// Records a failed request. Only errors that say upstream is unwell count.
+    pub fn failure(&self, error: &Error) {
+        let retry_after = match error {
+            Error::RateLimited(after) => Some(*after),
+            Error::Network(_) | Error::Upstream(_) => None,
+            _ => {
+                // upstream answered, so the probe is over
+                self.inner.lock().unwrap().probing = false;
+                return;
+            }
+        };
+        let mut inner = self.inner.lock().unwrap();
+        inner.failures += 1;
+        inner.probing = false;
+        let trip = retry_after.is_some()
+            || inner.state == BreakerState::HalfOpen
+            || inner.failures >= self.config.failure_threshold;
+        if !trip {
+            return;
+        }
+        inner.trips += 1;
+        let backoff = jitter(backoff(
+            Duration::from_secs(self.config.backoff),
+            inner.trips,
+            Duration::from_secs(self.config.max_backoff),
+        ));
+        // Retry-After wins if upstream asks for a longer pause
+        let pause = retry_after
+            .flatten()
+            .map_or(backoff, |after| after.max(backoff));
+        let now = unix_now();
+        inner.state = BreakerState::Open;
+        inner.open_since.get_or_insert(now);
+        inner.retry_at = now + pause.as_secs();
+        warn!(
+            kind = error.kind(),
+            "Circuit breaker open for {}s after {} failures: {error}",
+            pause.as_secs(),
+            inner.failures
+        );
+    }
+
+    /// Time of the next probe while the breaker is open and it is not due yet
+    pub fn blocked_until(&self) -> Option<u64> {
+        let inner = self.inner.lock().unwrap();
+        (inner.state == BreakerState::Open && unix_now() < inner.retry_at).then_some(inner.retry_at)
+    }
+
+    /// Since when calendars are served from old data, `None` while closed
+    pub fn open_since(&self) -> Option<u64> {
+        self.inner.lock().unwrap().open_since
+    }
+
+    pub fn status(&self) -> BreakerStatus {
+        let inner = self.inner.lock().unwrap();
+        BreakerStatus {
+            state: inner.state,
+            consecutive_failures: inner.failures,
+            open_since: inner.open_since,
+            retry_at: (inner.state != BreakerState::Closed).then_some(inner.retry_at),
+        }
+    }
+}
+
+/// `base` for the first attempt, doubled for every further one, at most `max`
+pub fn backoff(base: Duration, attempt: u32, max: Duration) -> Duration {
+    base.saturating_mul(1 << attempt.saturating_sub(1).min(31))
+        .min(max)
+}
+
+/// Spreads `d` randomly over 80% to 120% so the tasks of all tenants do not probe
+/// at the same moment
+pub fn jitter(d: Duration) -> Duration {
+    let random = RandomState::new().hash_one(unix_now());
+    d.mul_f64(0.8 + (random % 1000) as f64 / 2500.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use hyper::{header::RETRY_AFTER, StatusCode};
+
+    use super::*;
+
+    fn breaker() -> Breaker {
+        Breaker::new(BreakerConfig {
+            failure_threshold: 2,
+            backoff: 60,
+            max_backoff: 600,
+        })
+    }
+
+    fn upstream() -> Error {
+        Error::Upstream(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    /// Seconds until the next probe, `None` while the breaker lets requests through
+    fn retry_in(breaker: &Breaker) -> Option<u64> {
+        match breaker.acquire() {
+            Ok(()) => None,
+            Err(Error::CircuitOpen(at)) => Some(at - unix_now()),
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    /// Lets the backoff of an open breaker run out
+    fn elapse(breaker: &Breaker) {
+        breaker.inner.lock().unwrap().retry_at = unix_now();
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let (base, max) = (Duration::from_secs(10), Duration::from_secs(100));
+        let delays = (0..6)
+            .map(|n| backoff(base, n, max).as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, [10, 10, 20, 40, 80, 100]);
+        assert_eq!(backoff(base, u32::MAX, max), max);
+        assert_eq!(
+            backoff(Duration::MAX, 40, Duration::MAX),
+            Duration::MAX,
+            "saturates instead of overflowing"
+        );
+    }
+
+    #[test]
+    fn opens_after_threshold() {
+        let breaker = breaker();
+        breaker.failure(&upstream());
+        assert_eq!(retry_in(&breaker), None);
+        assert_eq!(breaker.status().state, BreakerState::Closed);
+
+        breaker.failure(&upstream());
+        let status = breaker.status();
+        assert_eq!(status.state, BreakerState::Open);
+        assert_eq!(status.consecutive_failures, 2);
+        assert!(status.open_since.is_some());
+        assert!((47..=72).contains(&retry_in(&breaker).unwrap()));
+        assert!(breaker.blocked_until().is_some());
+    }
+
+    #[test]
+    fn answers_that_are_not_outages_do_not_count() {
+        let breaker = breaker();
+        for _ in 0..5 {
+            breaker.failure(&Error::Status(StatusCode::NOT_FOUND));
+            breaker.failure(&Error::SessionRejected(StatusCode::UNAUTHORIZED));
+        }
+        assert_eq!(breaker.status().state, BreakerState::Closed);
+        assert_eq!(breaker.status().consecutive_failures, 0);
+    }
+
+    #[test]
+    fn half_open_probe_closes() {
+        let breaker = breaker();
+        breaker.failure(&upstream());
+        breaker.failure(&upstream());
+        elapse(&breaker);
+
+        assert_eq!(retry_in(&breaker), None);
+        assert_eq!(breaker.status().state, BreakerState::HalfOpen);
+        // only one probe at a time
+        assert!(retry_in(&breaker).is_some());
+        assert!(breaker.blocked_until().is_none());
+
+        breaker.success();
+        let status = breaker.status();
+        assert_eq!(status.state, BreakerState::Closed);
+        assert_eq!(status.consecutive_failures, 0);
+        assert_eq!(status.open_since, None);
+        assert_eq!(retry_in(&breaker), None);
+    }
+
+    #[test]
+    fn failed_probe_reopens_with_doubled_backoff() {
+        let breaker = breaker();
+        breaker.failure(&upstream());
+        breaker.failure(&upstream());
+        let open_since = breaker.open_since();
+        elapse(&breaker);
+        retry_in(&breaker);
+
+        breaker.failure(&upstream());
+        assert_eq!(breaker.status().state, BreakerState::Open);
+        assert_eq!(breaker.open_since(), open_since);
+        assert!((95..=144).contains(&retry_in(&breaker).unwrap()));
+
+        // the third trip would be 240s, the fifth is capped at max_backoff
+        for _ in 0..3 {
+            elapse(&breaker);
+            retry_in(&breaker);
+            breaker.failure(&upstream());
+        }
+        assert!((479..=720).contains(&retry_in(&breaker).unwrap()));
+    }
+
+    #[test]
+    fn probe_that_never_reports_back_times_out() {
+        let breaker = breaker();
+        breaker.failure(&upstream());
+        breaker.failure(&upstream());
+        elapse(&breaker);
+        retry_in(&breaker);
+        assert!(retry_in(&breaker).is_some());
+
+        elapse(&breaker);
+        assert_eq!(retry_in(&breaker), None);
+    }
+
+    #[test]
+    fn retry_after_takes_precedence() {
+        let breaker = breaker();
+        // a single 429 is enough, and its longer Retry-After wins over the backoff
+        breaker.failure(&Error::RateLimited(Some(Duration::from_secs(3600))));
+        assert_eq!(breaker.status().state, BreakerState::Open);
+        assert!((3599..=3600).contains(&retry_in(&breaker).unwrap()));
+
+        // a shorter one does not shorten the backoff
+        let breaker = self::breaker();
+        breaker.failure(&Error::RateLimited(Some(Duration::from_secs(1))));
+        assert!((47..=72).contains(&retry_in(&breaker).unwrap()));
+
+        let breaker = self::breaker();
+        breaker.failure(&Error::RateLimited(None));
+        assert!((47..=72).contains(&retry_in(&breaker).unwrap()));
+
+        // proxies often send an HTTP date instead of seconds
+        let at = Utc::now() + chrono::Duration::hours(1);
+        let res = hyper::Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header(
+                RETRY_AFTER,
+                at.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+            )
+            .body("")
+            .unwrap();
+        let error = Error::from_response(&res.into()).unwrap();
+        let breaker = self::breaker();
+        breaker.failure(&error);
+        assert!((3598..=3600).contains(&retry_in(&breaker).unwrap()));
+    }
+}
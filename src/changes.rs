@@ -0,0 +1,402 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use chrono::NaiveDate;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::{
+    definitions::{CalendarEntry, Status},
+    fetch::{unix_now, DayResult},
+};
+
+/// Changes kept per tenant, older ones are dropped first
+const MAX_CHANGES: usize = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Cancelled,
+    /// A cancelled lesson takes place again
+    Reinstated,
+    Moved {
+        from: String,
+        to: String,
+    },
+    RoomChanged {
+        from: Vec<String>,
+        to: Vec<String>,
+    },
+    Substitution {
+        from: Vec<String>,
+        to: Vec<String>,
+    },
+    Homework {
+        text: String,
+    },
+}
+
+impl ChangeKind {
+    pub const NAMES: [&str; 8] = [
+        "added",
+        "removed",
+        "cancelled",
+        "reinstated",
+        "moved",
+        "room_changed",
+        "substitution",
+        "homework",
+    ];
+
+    /// Name of the change type as in the `type` field of the JSON
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChangeKind::Added => "added",
+            ChangeKind::Removed => "removed",
+            ChangeKind::Cancelled => "cancelled",
+            ChangeKind::Reinstated => "reinstated",
+            ChangeKind::Moved { .. } => "moved",
+            ChangeKind::RoomChanged { .. } => "room_changed",
+            ChangeKind::Substitution { .. } => "substitution",
+            ChangeKind::Homework { .. } => "homework",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Change {
+    /// Unix timestamp at which the change was logged, never lower than that of the
+    /// changes before it
+    pub at: u64,
+    pub element: isize,
+    pub day: NaiveDate,
+    pub lesson: i64,
+    pub subject: Option<String>,
+    pub start: String,
+    pub end: String,
+    #[serde(flatten)]
+    pub kind: ChangeKind,
+}
+
+/// Recent changes of all elements of a tenant, oldest first
+pub struct ChangeLog {
+    log: Mutex<VecDeque<Change>>,
+    /// Every batch of changes as it is pushed
+    events: broadcast::Sender<Arc<Vec<Change>>>,
+}
+
+impl Default for ChangeLog {
+    fn default() -> Self {
+        Self {
+            log: Mutex::default(),
+            events: broadcast::Sender::new(16),
+        }
+    }
+}
+
+impl ChangeLog {
+    /// Stamps and logs the changes of one update and returns them as sent to the
+    /// subscribers. Stamping under the lock keeps the log sorted for `since`.
+    pub fn push(&self, mut changes: Vec<Change>) -> Arc<Vec<Change>> {
+        let mut log = self.log.lock().unwrap();
+        let at = unix_now().max(log.back().map_or(0, |c| c.at));
+        for change in &mut changes {
+            change.at = at;
+        }
+        let changes = Arc::new(changes);
+        // fails only if nobody listens
+        self.events.send(changes.clone()).ok();
+        log.extend(changes.iter().cloned());
+        let excess = log.len().saturating_sub(MAX_CHANGES);
+        log.drain(..excess);
+        changes
+    }
+
+    /// Changes noticed at or after the unix timestamp `since`
+    pub fn since(&self, since: u64) -> Vec<Change> {
+        let log = self.log.lock().unwrap();
+        let start = log.partition_point(|c| c.at < since);
+        log.range(start..).cloned().collect()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Vec<Change>>> {
+        self.events.subscribe()
+    }
+}
+
+/// Compares the freshly fetched days with the lessons they replace. Days that were
+/// not known before, e.g. when the window moves on, are not reported. The changes are
+/// stamped when they are pushed to the log.
+pub fn diff(
+    element: isize,
+    entries: &BTreeMap<NaiveDate, Vec<CalendarEntry>>,
+    results: &BTreeMap<NaiveDate, DayResult>,
+) -> Vec<Change> {
+    let mut old = HashMap::new();
+    let mut new = HashMap::new();
+    for (day, result) in results {
+        let (Some(before), DayResult::Fetched(after)) = (entries.get(day), result) else {
+            continue;
+        };
+        old.extend(before.iter().map(|l| (l.id, (*day, l))));
+        new.extend(after.iter().map(|l| (l.id, (*day, l))));
+    }
+
+    let change = |day: NaiveDate, lesson: &CalendarEntry, kind| Change {
+        at: 0,
+        element,
+        day,
+        lesson: lesson.id,
+        subject: lesson.subject.as_ref().map(|s| s.display_name.clone()),
+        start: lesson.start_date_time.clone(),
+        end: lesson.end_date_time.clone(),
+        kind,
+    };
+    let mut changes = Vec::new();
+    for (id, (day, before)) in &old {
+        if !new.contains_key(id) {
+            changes.push(change(*day, before, ChangeKind::Removed));
+        }
+    }
+    for (id, (day, after)) in &new {
+        let Some((_, before)) = old.get(id) else {
+            changes.push(change(*day, after, ChangeKind::Added));
+            continue;
+        };
+        let cancelled = |l: &CalendarEntry| l.status == Status::Cancelled;
+        match (cancelled(before), cancelled(after)) {
+            (false, true) => changes.push(change(*day, after, ChangeKind::Cancelled)),
+            (true, false) => changes.push(change(*day, after, ChangeKind::Reinstated)),
+            _ => {}
+        }
+        if before.start_date_time != after.start_date_time {
+            let kind = ChangeKind::Moved {
+                from: before.start_date_time.clone(),
+                to: after.start_date_time.clone(),
+            };
+            changes.push(change(*day, after, kind));
+        }
+        let rooms = |l: &CalendarEntry| l.rooms.iter().map(|r| r.short_name.clone()).collect();
+        let (from, to): (Vec<_>, Vec<_>) = (rooms(before), rooms(after));
+        if from != to {
+            changes.push(change(*day, after, ChangeKind::RoomChanged { from, to }));
+        }
+        let teachers =
+            |l: &CalendarEntry| l.teachers.iter().map(|t| t.short_name.clone()).collect();
+        let (from, to): (Vec<_>, Vec<_>) = (teachers(before), teachers(after));
+        if from != to {
+            changes.push(change(*day, after, ChangeKind::Substitution { from, to }));
+        }
+        let known = before
+            .homeworks
+            .iter()
+            .map(|h| h.id)
+            .collect::<HashSet<_>>();
+        for homework in after.homeworks.iter().filter(|h| !known.contains(&h.id)) {
+            let kind = ChangeKind::Homework {
+                text: homework.text.clone(),
+            };
+            changes.push(change(*day, after, kind));
+        }
+    }
+    changes.sort_by(|a, b| (a.day, &a.start, a.lesson).cmp(&(b.day, &b.start, b.lesson)));
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::definitions::{Homework, Room, Subject, Teacher};
+
+    use super::*;
+
+    fn day() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2026, 10, 19).unwrap()
+    }
+
+    fn lesson(id: i64) -> CalendarEntry {
+        CalendarEntry {
+            id,
+            start_date_time: "2026-10-19T08:00".to_owned(),
+            end_date_time: "2026-10-19T08:45".to_owned(),
+            subject: Some(Subject {
+                display_name: "MA1".to_owned(),
+                ..Default::default()
+            }),
+            rooms: vec![Room {
+                short_name: "A1".to_owned(),
+                ..Default::default()
+            }],
+            teachers: vec![Teacher {
+                short_name: "MUE".to_owned(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    /// Changes between the lessons of one known day
+    fn changes(before: Vec<CalendarEntry>, after: Vec<CalendarEntry>) -> Vec<ChangeKind> {
+        let entries = BTreeMap::from([(day(), before)]);
+        let results = BTreeMap::from([(day(), DayResult::Fetched(after))]);
+        diff(-5, &entries, &results)
+            .into_iter()
+            .map(|c| c.kind)
+            .collect()
+    }
+
+    #[test]
+    fn unchanged() {
+        assert!(changes(vec![lesson(1)], vec![lesson(1)]).is_empty());
+    }
+
+    #[test]
+    fn added_and_removed() {
+        assert_eq!(
+            changes(vec![lesson(1)], vec![lesson(2)]),
+            [ChangeKind::Removed, ChangeKind::Added]
+        );
+    }
+
+    #[test]
+    fn cancelled_and_reinstated() {
+        let cancelled = CalendarEntry {
+            status: Status::Cancelled,
+            ..lesson(1)
+        };
+        assert_eq!(
+            changes(vec![lesson(1)], vec![cancelled.clone()]),
+            [ChangeKind::Cancelled]
+        );
+        assert_eq!(
+            changes(vec![cancelled], vec![lesson(1)]),
+            [ChangeKind::Reinstated]
+        );
+    }
+
+    #[test]
+    fn moved() {
+        let moved = CalendarEntry {
+            start_date_time: "2026-10-19T10:00".to_owned(),
+            ..lesson(1)
+        };
+        assert_eq!(
+            changes(vec![lesson(1)], vec![moved]),
+            [ChangeKind::Moved {
+                from: "2026-10-19T08:00".to_owned(),
+                to: "2026-10-19T10:00".to_owned(),
+            }]
+        );
+    }
+
+    #[test]
+    fn room_and_teacher() {
+        let mut changed = lesson(1);
+        changed.rooms[0].short_name = "B2".to_owned();
+        changed.teachers.push(Teacher {
+            short_name: "SCH".to_owned(),
+            ..Default::default()
+        });
+        assert_eq!(
+            changes(vec![lesson(1)], vec![changed]),
+            [
+                ChangeKind::RoomChanged {
+                    from: vec!["A1".to_owned()],
+                    to: vec!["B2".to_owned()],
+                },
+                ChangeKind::Substitution {
+                    from: vec!["MUE".to_owned()],
+                    to: vec!["MUE".to_owned(), "SCH".to_owned()],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn only_new_homework() {
+        let homework = |id: i64, text: &str| Homework {
+            id,
+            text: text.to_owned(),
+            ..Default::default()
+        };
+        let before = CalendarEntry {
+            homeworks: vec![homework(1, "S. 12")],
+            ..lesson(1)
+        };
+        let after = CalendarEntry {
+            homeworks: vec![homework(1, "S. 12"), homework(2, "S. 13")],
+            ..lesson(1)
+        };
+        assert_eq!(
+            changes(vec![before.clone()], vec![after]),
+            [ChangeKind::Homework {
+                text: "S. 13".to_owned()
+            }]
+        );
+        // homework that is gone is not a change
+        assert!(changes(vec![before], vec![lesson(1)]).is_empty());
+    }
+
+    #[test]
+    fn unknown_and_failed_days_are_not_reported() {
+        let other = day().succ_opt().unwrap();
+        let entries = BTreeMap::from([(day(), vec![lesson(1)])]);
+        let results = BTreeMap::from([
+            (day(), DayResult::Failed("timeout".to_owned())),
+            (other, DayResult::Fetched(vec![lesson(2)])),
+        ]);
+        assert!(diff(-5, &entries, &results).is_empty());
+    }
+
+    #[test]
+    fn lesson_moved_to_another_known_day() {
+        let other = day().succ_opt().unwrap();
+        let moved = CalendarEntry {
+            start_date_time: "2026-10-20T08:00".to_owned(),
+            ..lesson(1)
+        };
+        let entries = BTreeMap::from([(day(), vec![lesson(1)]), (other, vec![])]);
+        let results = BTreeMap::from([
+            (day(), DayResult::Fetched(vec![])),
+            (other, DayResult::Fetched(vec![moved])),
+        ]);
+        let changes = diff(-5, &entries, &results);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].day, other);
+        assert!(matches!(changes[0].kind, ChangeKind::Moved { .. }));
+    }
+
+    #[test]
+    fn push_stamps_in_order() {
+        let log = ChangeLog::default();
+        let mut events = log.subscribe();
+        let change = |lesson| Change {
+            at: 0,
+            element: -5,
+            day: day(),
+            lesson,
+            subject: None,
+            start: String::new(),
+            end: String::new(),
+            kind: ChangeKind::Added,
+        };
+        let before = unix_now();
+        let first = log.push(vec![change(1)]);
+        assert!((before..=unix_now()).contains(&first[0].at));
+
+        // a clock that went back must not put the next batch before older ones
+        let last = unix_now() + 100;
+        log.log.lock().unwrap().back_mut().unwrap().at = last;
+        let pushed = log.push(vec![change(2), change(3)]);
+        assert!(pushed.iter().all(|c| c.at == last));
+        assert_eq!(events.try_recv().unwrap().len(), 1);
+        assert_eq!(events.try_recv().unwrap().len(), 2);
+
+        assert_eq!(log.since(0).len(), 3);
+        assert_eq!(log.since(last).len(), 3);
+        assert!(log.since(last + 1).is_empty());
+    }
+}
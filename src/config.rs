@@ -0,0 +1,825 @@
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    net::SocketAddr,
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+};
+
+use chrono::{NaiveDate, NaiveTime};
+use lettre::message::Mailbox;
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::{changes::ChangeKind, secret::SecretRef};
+
+const DEFAULT_CONFIG_PATH: &str = "./config.toml";
+
+fn default_state_dir() -> PathBuf {
+    PathBuf::from("./state")
+}
+
+fn default_alias() -> String {
+    "./alias".to_owned()
+}
+
+fn default_rate_limit() -> NonZeroU32 {
+    NonZeroU32::new(50).unwrap()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    #[serde(rename = "school")]
+    pub schools: Vec<SchoolConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: SocketAddr,
+    /// Bearer token for the `/admin` endpoints, they are disabled if unset
+    pub admin_token: Option<SecretRef>,
+    /// Directory for the encrypted session files and their key
+    #[serde(default = "default_state_dir")]
+    pub state_dir: PathBuf,
+    /// SQLite database that keeps every version of every lesson, disabled if unset
+    pub history: Option<PathBuf>,
+    /// Relay for the digest emails, required if any school has subscribers
+    pub smtp: Option<SmtpConfig>,
+    /// Broker for the MQTT profiles, required if any school has profiles
+    pub mqtt: Option<MqttConfig>,
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_client_id() -> String {
+    "untis".to_owned()
+}
+
+fn default_mqtt_prefix() -> String {
+    "untis".to_owned()
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_owned()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    pub username: Option<SecretRef>,
+    pub password: Option<SecretRef>,
+    /// Topics are published below `<prefix>/<school>/<profile>/`
+    #[serde(default = "default_mqtt_prefix")]
+    pub prefix: String,
+    /// Home Assistant discovery prefix, no discovery payloads are sent if empty
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Plain connection, e.g. for a local MailHog
+    None,
+    #[default]
+    Starttls,
+    /// TLS from the start, usually on port 465
+    Tls,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SmtpConfig {
+    pub host: String,
+    /// Defaults to the port of the `tls` mode
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub tls: SmtpTls,
+    pub username: Option<SecretRef>,
+    pub password: Option<SecretRef>,
+    /// Sender address, e.g. `Stundenplan <untis@example.com>`
+    pub from: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SchoolConfig {
+    /// Identifier used in the URL prefix `/s/<id>/`
+    pub id: String,
+    /// Base URL of the WebUntis instance, e.g. `https://nessa.webuntis.com`
+    pub untis_url: String,
+    /// Base URL of the IServ portal that acts as the OAuth server, credentials are only
+    /// sent to this host
+    pub iserv_url: Option<String>,
+    /// Value of the `schoolname` cookie WebUntis sets after selecting the school
+    pub school_name: String,
+    /// Value of the `Tenant-Id` cookie
+    pub tenant_id: String,
+    pub grades: Vec<isize>,
+    /// Grade used for `/` and `/ics`, defaults to the first entry of `grades`
+    pub default_grade: Option<isize>,
+    /// Fallback address for lessons without a location alias
+    pub location: String,
+    pub negative_offset: u64,
+    pub positive_offset: usize,
+    /// Path of the alias file, see the README
+    #[serde(default = "default_alias")]
+    pub alias: String,
+    /// Upstream requests per second during school days
+    #[serde(default = "default_rate_limit")]
+    pub rate_limit: NonZeroU32,
+    #[serde(default)]
+    pub polling: PollingConfig,
+    #[serde(default)]
+    pub credentials: CredentialsConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub lockout: LockoutConfig,
+    #[serde(default)]
+    pub fetch: FetchConfig,
+    #[serde(default)]
+    pub sanity: SanityConfig,
+    #[serde(default)]
+    pub breaker: BreakerConfig,
+    #[serde(default, rename = "webhook")]
+    pub webhooks: Vec<WebhookConfig>,
+    #[serde(default, rename = "subscriber")]
+    pub subscribers: Vec<SubscriberConfig>,
+    #[serde(default, rename = "profile")]
+    pub profiles: Vec<ProfileConfig>,
+    /// Refresh intervals for the parts of the window, nearest first
+    #[serde(default = "default_tiers", rename = "tier")]
+    pub tiers: Vec<TierConfig>,
+    /// Only used if the authorize page does not contain these values
+    #[serde(default)]
+    pub oauth: OAuthConfig,
+}
+
+/// Protection of the account against lockouts by repeated failed logins
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LockoutConfig {
+    /// Rejected logins after which no further attempts are made
+    pub max_failures: u32,
+    /// Seconds to wait after the first rejected login, doubled after each further one
+    pub backoff: u64,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: 3,
+            backoff: 300,
+        }
+    }
+}
+
+/// How the timetable window is split into upstream requests
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FetchConfig {
+    /// Largest range asked for in one request, in days. Ranges that fail are halved
+    /// down to single days.
+    pub batch_days: u32,
+    /// Responses larger than this are split into smaller ranges, in KiB
+    pub max_response_kb: usize,
+    /// Seconds one request may take including its response, slower ones are split
+    pub timeout: u64,
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        Self {
+            batch_days: 14,
+            max_response_kb: 4096,
+            timeout: 30,
+        }
+    }
+}
+
+/// Scales the tier intervals and the rate limit with the time of day and the calendar
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PollingConfig {
+    /// School mornings, when changes for the day come in
+    pub peak: (NaiveTime, NaiveTime),
+    pub night: (NaiveTime, NaiveTime),
+    /// Tier intervals are multiplied by these factors
+    pub peak_factor: f64,
+    pub night_factor: f64,
+    /// Weekends and holidays
+    pub free_day_factor: f64,
+    /// Upstream requests per second at night and on free days
+    pub off_peak_rate_limit: NonZeroU32,
+    /// First and last day of each holiday. Weekdays on which the default grade has
+    /// no lessons count as holidays as well.
+    pub holidays: Vec<(NaiveDate, NaiveDate)>,
+}
+
+impl Default for PollingConfig {
+    fn default() -> Self {
+        Self {
+            peak: (
+                NaiveTime::from_hms_opt(6, 0, 0).unwrap(),
+                NaiveTime::from_hms_opt(9, 0, 0).unwrap(),
+            ),
+            night: (
+                NaiveTime::from_hms_opt(21, 0, 0).unwrap(),
+                NaiveTime::from_hms_opt(5, 0, 0).unwrap(),
+            ),
+            peak_factor: 0.5,
+            night_factor: 6.0,
+            free_day_factor: 8.0,
+            off_peak_rate_limit: NonZeroU32::new(2).unwrap(),
+            holidays: Vec::new(),
+        }
+    }
+}
+
+/// Stops requests to WebUntis while it fails
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BreakerConfig {
+    /// Failed requests in a row after which the breaker opens
+    pub failure_threshold: u32,
+    /// Seconds the breaker stays open the first time, doubled each time a probe fails
+    pub backoff: u64,
+    pub max_backoff: u64,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            backoff: 30,
+            max_backoff: 30 * 60,
+        }
+    }
+}
+
+fn default_webhook_events() -> Vec<String> {
+    ["cancelled", "room_changed", "homework"]
+        .map(str::to_owned)
+        .to_vec()
+}
+
+fn default_max_attempts() -> u32 {
+    5
+}
+
+/// Whether a lesson of `subject` belongs to the `courses` of a webhook, subscriber or
+/// profile. An empty list stands for all courses, lessons without a subject always
+/// belong to them.
+pub fn in_courses(courses: &[String], subject: Option<&str>) -> bool {
+    courses.is_empty() || subject.is_none_or(|s| courses.iter().any(|c| c == s))
+}
+
+/// Receiver of the changes to some courses of the default grade
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Course shorthands as in `/ics`, all courses if empty
+    #[serde(default)]
+    pub courses: Vec<String>,
+    /// Change types that are sent, see `/changes`
+    #[serde(default = "default_webhook_events")]
+    pub events: Vec<String>,
+    /// Key for the HMAC-SHA256 signature in `X-Untis-Signature`
+    pub secret: Option<SecretRef>,
+    /// Deliveries that failed this often go to the dead-letter log
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+}
+
+/// Courses of the default grade published over MQTT
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileConfig {
+    /// Used in the topics, e.g. the name of a child
+    pub name: String,
+    /// Course shorthands as in `/ics`, all courses if empty
+    #[serde(default)]
+    pub courses: Vec<String>,
+}
+
+fn default_digest_time() -> NaiveTime {
+    NaiveTime::from_hms_opt(18, 0, 0).unwrap()
+}
+
+/// Daily email with tomorrow's changes to some courses of the default grade
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SubscriberConfig {
+    pub email: String,
+    /// Course shorthands as in `/ics`, all courses if empty
+    #[serde(default)]
+    pub courses: Vec<String>,
+    /// Local time at which the digest is sent
+    #[serde(default = "default_digest_time")]
+    pub at: NaiveTime,
+    /// Also send the digest if nothing changes tomorrow
+    #[serde(default)]
+    pub send_empty: bool,
+}
+
+/// Holds back updates in which too many lessons vanished until the next fetch
+/// confirms them
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SanityConfig {
+    pub max_vanished_percent: u8,
+    /// Updates of fewer lessons than this are always applied
+    pub min_lessons: usize,
+}
+
+impl Default for SanityConfig {
+    fn default() -> Self {
+        Self {
+            max_vanished_percent: 40,
+            min_lessons: 10,
+        }
+    }
+}
+
+/// Part of the timetable window that is refreshed at its own interval
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TierConfig {
+    /// The tier ends this many days after today and starts where the previous tier
+    /// ends. Only the last tier omits it, it covers the rest of the window including
+    /// the past days.
+    pub days: Option<u64>,
+    /// Seconds between two refreshes
+    pub interval: u64,
+}
+
+fn default_tiers() -> Vec<TierConfig> {
+    vec![
+        TierConfig {
+            days: Some(2),
+            interval: 120,
+        },
+        TierConfig {
+            days: Some(14),
+            interval: 900,
+        },
+        TierConfig {
+            days: None,
+            interval: 3 * 60 * 60,
+        },
+    ]
+}
+
+/// How the service account logs into WebUntis
+#[derive(Debug, Default, Deserialize)]
+#[serde(tag = "provider", rename_all = "lowercase")]
+pub enum AuthConfig {
+    /// IServ as OAuth server, the default
+    #[default]
+    Iserv,
+    /// Username and password directly at WebUntis
+    Webuntis {
+        /// Login name of the school, derived from `school_name` if unset
+        school: Option<String>,
+    },
+    /// Generic OIDC identity provider with a login form
+    Oidc {
+        /// Base URL of the identity provider, credentials are only sent to this host
+        issuer: String,
+        /// Names of the login form fields, detected from the input types if unset
+        username_field: Option<String>,
+        password_field: Option<String>,
+    },
+}
+
+/// Where the login of this school is read from
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CredentialsConfig {
+    #[serde(default = "default_username")]
+    pub username: SecretRef,
+    #[serde(default = "default_password")]
+    pub password: SecretRef,
+    /// Base32 TOTP secret, for accounts with two factor login
+    pub totp: Option<SecretRef>,
+}
+
+impl Default for CredentialsConfig {
+    fn default() -> Self {
+        Self {
+            username: default_username(),
+            password: default_password(),
+            totp: None,
+        }
+    }
+}
+
+fn default_username() -> SecretRef {
+    SecretRef::env("USERNAME")
+}
+
+fn default_password() -> SecretRef {
+    SecretRef::env("PASSWORD")
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OAuthConfig {
+    pub client_id: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub scope: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, std::io::Error),
+    Parse(String, toml::de::Error),
+    Invalid(String, String),
+    /// School id, path and why its alias file can not be used
+    Alias(String, String, std::io::Error),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "could not read config file {path}: {e}"),
+            ConfigError::Parse(path, e) => write!(f, "could not parse config file {path}: {e}"),
+            ConfigError::Invalid(field, reason) => {
+                write!(f, "invalid value for `{field}`: {reason}")
+            }
+            ConfigError::Alias(school, path, e) => {
+                write!(f, "could not read alias file {path} of {school}: {e}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Loads the config from the path given as first argument, `$CONFIG` or `./config.toml`
+    pub fn load() -> Result<Self, ConfigError> {
+        let path = std::env::args()
+            .nth(1)
+            .or_else(|| std::env::var("CONFIG").ok())
+            .unwrap_or(DEFAULT_CONFIG_PATH.to_owned());
+        Self::from_file(path)
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let display = path.as_ref().display().to_string();
+        let content =
+            std::fs::read_to_string(&path).map_err(|e| ConfigError::Io(display.clone(), e))?;
+        let config =
+            toml::from_str::<Config>(&content).map_err(|e| ConfigError::Parse(display, e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.schools.is_empty() {
+            return Err(ConfigError::Invalid(
+                "school".to_owned(),
+                "at least one school is required".to_owned(),
+            ));
+        }
+        if let Some(mqtt) = &self.server.mqtt {
+            non_empty("server.mqtt.host".to_owned(), &mqtt.host)?;
+            non_empty("server.mqtt.prefix".to_owned(), &mqtt.prefix)?;
+        }
+        if let Some(smtp) = &self.server.smtp {
+            non_empty("server.smtp.host".to_owned(), &smtp.host)?;
+            validate_mailbox("server.smtp.from".to_owned(), &smtp.from)?;
+        }
+        let mut ids = HashSet::new();
+        for (i, school) in self.schools.iter().enumerate() {
+            let prefix = format!("school[{i}]");
+            school.validate(&prefix)?;
+            if !school.profiles.is_empty() && self.server.mqtt.is_none() {
+                return Err(ConfigError::Invalid(
+                    format!("{prefix}.profile"),
+                    "profiles need a [server.mqtt] broker".to_owned(),
+                ));
+            }
+            if !school.subscribers.is_empty() && self.server.smtp.is_none() {
+                return Err(ConfigError::Invalid(
+                    format!("{prefix}.subscriber"),
+                    "digests need a [server.smtp] relay".to_owned(),
+                ));
+            }
+            if !ids.insert(&school.id) {
+                return Err(ConfigError::Invalid(
+                    format!("{prefix}.id"),
+                    format!("{} is used by more than one school", school.id),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SchoolConfig {
+    fn validate(&self, prefix: &str) -> Result<(), ConfigError> {
+        let field = |name: &str| format!("{prefix}.{name}");
+        if !is_identifier(&self.id) {
+            return Err(ConfigError::Invalid(
+                field("id"),
+                format!("{:?} may only contain letters, digits, - and _", self.id),
+            ));
+        }
+        validate_url(field("untis_url"), &self.untis_url)?;
+        match (&self.auth, &self.iserv_url) {
+            (_, Some(url)) => validate_url(field("iserv_url"), url)?,
+            (AuthConfig::Iserv, None) => {
+                return Err(ConfigError::Invalid(
+                    field("iserv_url"),
+                    "is required for the iserv auth provider".to_owned(),
+                ))
+            }
+            _ => {}
+        }
+        if let AuthConfig::Oidc { issuer, .. } = &self.auth {
+            validate_url(field("auth.issuer"), issuer)?;
+        }
+        if let Some(uri) = &self.oauth.redirect_uri {
+            validate_url(field("oauth.redirect_uri"), uri)?;
+        }
+        non_empty(field("school_name"), &self.school_name)?;
+        non_empty(field("tenant_id"), &self.tenant_id)?;
+        non_empty(field("location"), &self.location)?;
+        non_empty(field("alias"), &self.alias)?;
+        if self.grades.is_empty() {
+            return Err(ConfigError::Invalid(
+                field("grades"),
+                "at least one grade is required".to_owned(),
+            ));
+        }
+        if let Some(g) = self.grades.iter().find(|g| **g <= 0) {
+            return Err(ConfigError::Invalid(
+                field("grades"),
+                format!("{g} is not a valid element id"),
+            ));
+        }
+        if let Some(d) = self.default_grade {
+            if !self.grades.contains(&d) {
+                return Err(ConfigError::Invalid(
+                    field("default_grade"),
+                    format!("{d} is not listed in {}", field("grades")),
+                ));
+            }
+        }
+        if self.lockout.max_failures == 0 {
+            return Err(ConfigError::Invalid(
+                field("lockout.max_failures"),
+                "must be greater than 0".to_owned(),
+            ));
+        }
+        if self.fetch.batch_days == 0 {
+            return Err(ConfigError::Invalid(
+                field("fetch.batch_days"),
+                "must be greater than 0".to_owned(),
+            ));
+        }
+        if self.fetch.timeout == 0 {
+            return Err(ConfigError::Invalid(
+                field("fetch.timeout"),
+                "must be greater than 0".to_owned(),
+            ));
+        }
+        let Some((last, rest)) = self.tiers.split_last() else {
+            return Err(ConfigError::Invalid(
+                field("tier"),
+                "at least one tier is needed".to_owned(),
+            ));
+        };
+        if last.days.is_some() {
+            return Err(ConfigError::Invalid(
+                field("tier.days"),
+                "the last tier covers the rest of the window and must not set days".to_owned(),
+            ));
+        }
+        let mut end = 0;
+        for tier in rest {
+            match tier.days {
+                Some(days) if days > end => end = days,
+                Some(days) => {
+                    return Err(ConfigError::Invalid(
+                        field("tier.days"),
+                        format!("{days} must be greater than the days of the tier before"),
+                    ))
+                }
+                None => {
+                    return Err(ConfigError::Invalid(
+                        field("tier.days"),
+                        "only the last tier may omit days".to_owned(),
+                    ))
+                }
+            }
+        }
+        if self.tiers.iter().any(|t| t.interval == 0) {
+            return Err(ConfigError::Invalid(
+                field("tier.interval"),
+                "must be greater than 0".to_owned(),
+            ));
+        }
+        if self.breaker.failure_threshold == 0 {
+            return Err(ConfigError::Invalid(
+                field("breaker.failure_threshold"),
+                "must be greater than 0".to_owned(),
+            ));
+        }
+        for (i, hook) in self.webhooks.iter().enumerate() {
+            let field = |name: &str| field(&format!("webhook[{i}].{name}"));
+            validate_url(field("url"), &hook.url)?;
+            if let Some(event) = hook
+                .events
+                .iter()
+                .find(|e| !ChangeKind::NAMES.contains(&e.as_str()))
+            {
+                return Err(ConfigError::Invalid(
+                    field("events"),
+                    format!(
+                        "unknown change type {event:?}, expected one of {}",
+                        ChangeKind::NAMES.join(", ")
+                    ),
+                ));
+            }
+            if hook.max_attempts == 0 {
+                return Err(ConfigError::Invalid(
+                    field("max_attempts"),
+                    "must be greater than 0".to_owned(),
+                ));
+            }
+        }
+        let mut names = HashSet::new();
+        for (i, profile) in self.profiles.iter().enumerate() {
+            let field = field(&format!("profile[{i}].name"));
+            if !is_identifier(&profile.name) {
+                return Err(ConfigError::Invalid(
+                    field,
+                    format!(
+                        "{:?} may only contain letters, digits, - and _",
+                        profile.name
+                    ),
+                ));
+            }
+            if !names.insert(&profile.name) {
+                return Err(ConfigError::Invalid(
+                    field,
+                    format!("{} is used by more than one profile", profile.name),
+                ));
+            }
+        }
+        for (i, subscriber) in self.subscribers.iter().enumerate() {
+            validate_mailbox(field(&format!("subscriber[{i}].email")), &subscriber.email)?;
+        }
+        if self.sanity.max_vanished_percent > 100 {
+            return Err(ConfigError::Invalid(
+                field("sanity.max_vanished_percent"),
+                "must be a percentage between 0 and 100".to_owned(),
+            ));
+        }
+        let polling = &self.polling;
+        for (name, factor) in [
+            ("polling.peak_factor", polling.peak_factor),
+            ("polling.night_factor", polling.night_factor),
+            ("polling.free_day_factor", polling.free_day_factor),
+        ] {
+            if !(factor > 0.0 && factor.is_finite()) {
+                return Err(ConfigError::Invalid(
+                    field(name),
+                    format!("{factor} is not a positive number"),
+                ));
+            }
+        }
+        if let Some((first, last)) = polling.holidays.iter().find(|(f, l)| f > l) {
+            return Err(ConfigError::Invalid(
+                field("polling.holidays"),
+                format!("{first} is after {last}"),
+            ));
+        }
+        if self.positive_offset == 0 {
+            return Err(ConfigError::Invalid(
+                field("positive_offset"),
+                "must be greater than 0".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn default_grade(&self) -> isize {
+        self.default_grade.unwrap_or(self.grades[0])
+    }
+
+    /// Cookies WebUntis expects for the selected school
+    pub fn cookies(&self) -> String {
+        format!(
+            "schoolname=\"{}\"; Tenant-Id=\"{}\";",
+            self.school_name, self.tenant_id
+        )
+    }
+
+    pub fn untis(&self, path: &str) -> String {
+        format!("{}{}", self.untis_url.trim_end_matches('/'), path)
+    }
+}
+
+fn validate_url(field: String, url: &str) -> Result<(), ConfigError> {
+    let parsed = match Url::parse(url) {
+        Ok(u) => u,
+        Err(e) => return Err(ConfigError::Invalid(field, format!("{url}: {e}"))),
+    };
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(ConfigError::Invalid(
+            field,
+            format!("{url}: only http and https are supported"),
+        ));
+    }
+    Ok(())
+}
+
+/// Whether `value` is a non-empty identifier of letters, digits, - and _
+fn is_identifier(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn validate_mailbox(field: String, value: &str) -> Result<(), ConfigError> {
+    match value.parse::<Mailbox>() {
+        Ok(_) => Ok(()),
+        Err(e) => Err(ConfigError::Invalid(field, format!("{value}: {e}"))),
+    }
+}
+
+fn non_empty(field: String, value: &str) -> Result<(), ConfigError> {
+    if value.trim().is_empty() {
+        return Err(ConfigError::Invalid(field, "must not be empty".to_owned()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{in_courses, ConfigError};
+    use crate::testutil::school;
+
+    #[test]
+    fn course_filter() {
+        let courses = vec!["MA1".to_owned(), "de2".to_owned()];
+        assert!(in_courses(&courses, Some("MA1")));
+        assert!(!in_courses(&courses, Some("DE2")));
+        assert!(!in_courses(&courses, Some("MA")));
+        assert!(in_courses(&courses, None));
+        assert!(in_courses(&[], Some("PH1")));
+    }
+
+    #[test]
+    fn partial_lockout_keeps_defaults() {
+        let school = school("https://untis.example.org", "[lockout]\nmax_failures = 5");
+        assert_eq!(school.lockout.max_failures, 5);
+        assert_eq!(school.lockout.backoff, 300);
+    }
+
+    #[test]
+    fn partial_fetch_keeps_defaults() {
+        let school = school("https://untis.example.org", "[fetch]\ntimeout = 5");
+        assert_eq!(school.fetch.timeout, 5);
+        assert_eq!(school.fetch.batch_days, 14);
+        assert_eq!(school.fetch.max_response_kb, 4096);
+    }
+
+    #[test]
+    fn tier_days_must_increase() {
+        let tiers = |days: &[u64]| {
+            let mut extra = "[auth]\nprovider = \"webuntis\"\n".to_owned();
+            extra += &days
+                .iter()
+                .map(|d| format!("[[tier]]\ndays = {d}\ninterval = 60\n"))
+                .collect::<String>();
+            extra.push_str("[[tier]]\ninterval = 600\n");
+            school("https://untis.example.org", &extra).validate("school[0]")
+        };
+        tiers(&[2, 14]).unwrap();
+        tiers(&[]).unwrap();
+        for days in [&[2, 2][..], &[14, 2]] {
+            match tiers(days) {
+                Err(ConfigError::Invalid(field, _)) => assert_eq!(field, "school[0].tier.days"),
+                res => panic!("{days:?} accepted: {res:?}"),
+            }
+        }
+    }
+}
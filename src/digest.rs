@@ -0,0 +1,548 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use chrono::{Datelike, Days, Local, NaiveDate};
+use lettre::{
+    message::{Mailbox, MultiPart},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use tracing::{error, info};
+
+use crate::{
+    config::{in_courses, SmtpConfig, SmtpTls, SubscriberConfig},
+    definitions::{CalendarEntry, Status},
+    feed::escape,
+    fetch::{is_additional, is_substitution},
+    secret::SecretRef,
+    tenant::Tenant,
+};
+
+const WEEKDAYS: [&str; 7] = [
+    "Montag",
+    "Dienstag",
+    "Mittwoch",
+    "Donnerstag",
+    "Freitag",
+    "Samstag",
+    "Sonntag",
+];
+
+/// Sends the digests of all schools through the configured relay
+pub struct Mailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl Mailer {
+    pub fn new(config: &SmtpConfig) -> Result<Self, String> {
+        let mut builder = match config.tls {
+            SmtpTls::None => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(
+                &config.host,
+            )),
+            SmtpTls::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host),
+            SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host),
+        }
+        .map_err(|e| e.to_string())?;
+        if let Some(port) = config.port {
+            builder = builder.port(port);
+        }
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            let resolve = |s: &SecretRef| {
+                s.resolve()
+                    .map(|s| s.expose().to_owned())
+                    .map_err(|e| e.to_string())
+            };
+            builder = builder.credentials(Credentials::new(resolve(username)?, resolve(password)?));
+        }
+        Ok(Self {
+            transport: builder.build(),
+            from: config.from.parse().map_err(|e| format!("{e}"))?,
+        })
+    }
+}
+
+/// One line of the digest
+struct Item {
+    time: String,
+    subject: String,
+    text: String,
+}
+
+/// Sends the digest of `subscriber` every day at its time, runs forever
+pub async fn run(tenant: Arc<Tenant>, mailer: Arc<Mailer>, subscriber: SubscriberConfig) {
+    loop {
+        let now = Local::now().naive_local();
+        let mut next = now.date().and_time(subscriber.at);
+        if next <= now {
+            next = next + Days::new(1);
+        }
+        tokio::time::sleep((next - now).to_std().unwrap_or_default()).await;
+
+        let tomorrow = next.date() + Days::new(1);
+        match send(&tenant, &mailer, &subscriber, tomorrow).await {
+            Ok(true) => info!("Digest an {} verschickt", subscriber.email),
+            Ok(false) => {}
+            Err(e) => error!("Digest an {} fehlgeschlagen: {e}", subscriber.email),
+        }
+    }
+}
+
+/// Mails the digest of `day` to `subscriber`, false if it was empty and not wanted
+async fn send(
+    tenant: &Tenant,
+    mailer: &Mailer,
+    subscriber: &SubscriberConfig,
+    day: NaiveDate,
+) -> Result<bool, String> {
+    let (lessons, homework) = collect(tenant, subscriber, day);
+    if lessons.is_empty() && homework.is_empty() && !subscriber.send_empty {
+        return Ok(false);
+    }
+    let heading = format!(
+        "Stundenplan für {}, {}",
+        WEEKDAYS[day.weekday().num_days_from_monday() as usize],
+        day.format("%d.%m.%Y")
+    );
+    let to = subscriber.email.parse().map_err(|e| format!("{e}"))?;
+    let message = Message::builder()
+        .from(mailer.from.clone())
+        .to(to)
+        .subject(&heading)
+        .multipart(MultiPart::alternative_plain_html(
+            plain(&heading, &lessons, &homework),
+            html(&heading, &lessons, &homework),
+        ))
+        .map_err(|e| e.to_string())?;
+    mailer
+        .transport
+        .send(message)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(true)
+}
+
+/// Changed lessons and homework due on `day` in the courses of `subscriber`
+fn collect(
+    tenant: &Tenant,
+    subscriber: &SubscriberConfig,
+    day: NaiveDate,
+) -> (Vec<Item>, Vec<Item>) {
+    let Some(data) = tenant.data.get(&tenant.default_key()) else {
+        return Default::default();
+    };
+    let data = data.shared_get();
+    let wanted = |entry: &CalendarEntry| {
+        let subject = entry.subject.as_ref().map(|s| s.display_name.as_str());
+        in_courses(&subscriber.courses, subject)
+    };
+    let name = |entry: &CalendarEntry| {
+        let subject = entry
+            .subject
+            .as_ref()
+            .map_or("", |s| s.display_name.as_str());
+        tenant
+            .alias
+            .get(subject)
+            .cloned()
+            .unwrap_or(subject.to_owned())
+    };
+    let time = |stamp: &str| stamp.get(11..16).unwrap_or_default().to_owned();
+
+    let lessons = data
+        .entries
+        .get(&day)
+        .into_iter()
+        .flatten()
+        .filter(|e| wanted(e))
+        .filter_map(|entry| {
+            let room = entry
+                .rooms
+                .iter()
+                .find(|r| r.status != Status::Removed)
+                .map_or("", |r| r.display_name.as_str());
+            let text = if entry.status == Status::Cancelled {
+                "fällt aus".to_owned()
+            } else if is_additional(entry) {
+                format!("➕ zusätzlich in {room}")
+            } else if is_substitution(entry) {
+                format!("🔄 Vertretung in {room}")
+            } else {
+                return None;
+            };
+            Some(Item {
+                time: time(&entry.start_date_time),
+                subject: name(entry),
+                text,
+            })
+        })
+        .collect();
+
+    // the same homework is attached to every lesson of its course
+    let due = day.format("%Y-%m-%d").to_string();
+    let homework = data
+        .entries
+        .values()
+        .flatten()
+        .filter(|e| wanted(e))
+        .flat_map(|entry| entry.homeworks.iter().map(move |hw| (hw, entry)))
+        .filter(|(hw, _)| hw.due_date_time.starts_with(&due))
+        .map(|(hw, entry)| {
+            (
+                hw.id,
+                Item {
+                    time: time(&hw.due_date_time),
+                    subject: name(entry),
+                    text: hw.text.clone(),
+                },
+            )
+        })
+        .collect::<BTreeMap<_, _>>()
+        .into_values()
+        .collect();
+    (lessons, homework)
+}
+
+fn plain(heading: &str, lessons: &[Item], homework: &[Item]) -> String {
+    let mut text = format!("{heading}\n\n");
+    if lessons.is_empty() {
+        text.push_str("Keine Änderungen.\n");
+    }
+    for item in lessons {
+        text.push_str(&format!("{} {}: {}\n", item.time, item.subject, item.text));
+    }
+    if !homework.is_empty() {
+        text.push_str("\nHausaufgaben:\n");
+        for item in homework {
+            text.push_str(&format!("{}: {}\n", item.subject, item.text));
+        }
+    }
+    text
+}
+
+fn html(heading: &str, lessons: &[Item], homework: &[Item]) -> String {
+    let mut html = format!("<h2>{}</h2>\n", escape(heading));
+    if lessons.is_empty() {
+        html.push_str("<p>Keine Änderungen.</p>\n");
+    } else {
+        html.push_str("<table>\n");
+        for item in lessons {
+            html.push_str(&format!(
+                "<tr><td>{}</td><td><b>{}</b></td><td>{}</td></tr>\n",
+                escape(&item.time),
+                escape(&item.subject),
+                escape(&item.text)
+            ));
+        }
+        html.push_str("</table>\n");
+    }
+    if !homework.is_empty() {
+        html.push_str("<h3>Hausaufgaben</h3>\n<ul>\n");
+        for item in homework {
+            html.push_str(&format!(
+                "<li><b>{}</b>: {}</li>\n",
+                escape(&item.subject),
+                escape(&item.text)
+            ));
+        }
+        html.push_str("</ul>\n");
+    }
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use arcshift::ArcShift;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use chrono::NaiveTime;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    use super::*;
+    use crate::{
+        definitions::{Homework, Room, Subject, Type},
+        testutil, TimeTableData,
+    };
+
+    fn day() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2026, 10, 20).unwrap()
+    }
+
+    fn lesson(day: NaiveDate, hour: u32, subject: &str) -> CalendarEntry {
+        let at = |hour| format!("{}T{hour:02}:00", day.format("%Y-%m-%d"));
+        CalendarEntry {
+            id: hour as i64,
+            start_date_time: at(hour),
+            end_date_time: at(hour + 1),
+            subject: Some(Subject {
+                display_name: subject.to_owned(),
+                ..Default::default()
+            }),
+            rooms: vec![Room {
+                display_name: "A1".to_owned(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn cancelled(mut lesson: CalendarEntry) -> CalendarEntry {
+        lesson.status = Status::Cancelled;
+        lesson
+    }
+
+    fn homework(id: i64, due: NaiveDate, text: &str) -> Homework {
+        Homework {
+            id,
+            due_date_time: format!("{}T08:00", due.format("%Y-%m-%d")),
+            text: text.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    /// Tenant whose default grade has these lessons
+    fn tenant(entries: BTreeMap<NaiveDate, Vec<CalendarEntry>>) -> (Tenant, tempfile::TempDir) {
+        let (mut tenant, dir) = testutil::tenant("http://127.0.0.1:1", "");
+        tenant
+            .alias
+            .insert("MA1".to_owned(), "Mathe <LK>".to_owned());
+        let data = TimeTableData {
+            entries,
+            ..Default::default()
+        };
+        tenant
+            .data
+            .insert(tenant.default_key(), ArcShift::new(data));
+        (tenant, dir)
+    }
+
+    /// The week around `day()`: changes tomorrow, a regular lesson, a course that is
+    /// not subscribed and changes on other days
+    fn week() -> BTreeMap<NaiveDate, Vec<CalendarEntry>> {
+        let today = day() - Days::new(1);
+        let after = day() + Days::new(1);
+        let mut substitution = lesson(day(), 9, "DE2");
+        substitution.rooms[0].status = Status::Substitution;
+        substitution.rooms.insert(
+            0,
+            Room {
+                display_name: "B2".to_owned(),
+                status: Status::Removed,
+                ..Default::default()
+            },
+        );
+        let mut additional = lesson(day(), 10, "PH1");
+        additional.type_field = Type::AddiotionalPeriod;
+        let mut today_ma1 = lesson(today, 8, "MA1");
+        today_ma1.homeworks = vec![
+            homework(1, day(), "<b>Seite 5</b> & 6"),
+            homework(2, after, "Seite 7"),
+        ];
+        let mut today_ku1 = lesson(today, 9, "KU1");
+        today_ku1.homeworks = vec![homework(3, day(), "Skizze")];
+        // the same homework again on the next lesson of the course
+        let mut after_ma1 = cancelled(lesson(after, 8, "MA1"));
+        after_ma1.homeworks = vec![homework(1, day(), "<b>Seite 5</b> & 6")];
+        BTreeMap::from([
+            (
+                today,
+                vec![cancelled(lesson(today, 10, "DE2")), today_ma1, today_ku1],
+            ),
+            (
+                day(),
+                vec![
+                    cancelled(lesson(day(), 8, "MA1")),
+                    substitution,
+                    additional,
+                    lesson(day(), 11, "DE2"),
+                    cancelled(lesson(day(), 12, "KU1")),
+                ],
+            ),
+            (after, vec![after_ma1]),
+        ])
+    }
+
+    fn subscriber(courses: &[&str], send_empty: bool) -> SubscriberConfig {
+        SubscriberConfig {
+            email: "eltern@example.org".to_owned(),
+            courses: courses.iter().map(|c| c.to_string()).collect(),
+            at: NaiveTime::from_hms_opt(18, 0, 0).unwrap(),
+            send_empty,
+        }
+    }
+
+    fn items(items: &[Item]) -> Vec<(&str, &str, &str)> {
+        items
+            .iter()
+            .map(|i| (i.time.as_str(), i.subject.as_str(), i.text.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn collects_changes_and_homework_of_tomorrow() {
+        let (tenant, _dir) = tenant(week());
+        let (lessons, homework) =
+            collect(&tenant, &subscriber(&["MA1", "DE2", "PH1"], false), day());
+        assert_eq!(
+            items(&lessons),
+            vec![
+                ("08:00", "Mathe <LK>", "fällt aus"),
+                ("09:00", "DE2", "🔄 Vertretung in A1"),
+                ("10:00", "PH1", "➕ zusätzlich in A1"),
+            ]
+        );
+        assert_eq!(
+            items(&homework),
+            vec![("08:00", "Mathe <LK>", "<b>Seite 5</b> & 6")]
+        );
+
+        // without courses everything is included
+        let (lessons, homework) = collect(&tenant, &subscriber(&[], false), day());
+        assert_eq!(lessons.len(), 4);
+        assert_eq!(homework.len(), 2);
+
+        let (lessons, homework) = collect(&tenant, &subscriber(&["EN1"], false), day());
+        assert!(lessons.is_empty() && homework.is_empty());
+    }
+
+    /// Minimal SMTP server on a free local port that accepts every mail and hands out
+    /// its raw data
+    async fn sink() -> (u16, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let (read, mut write) = stream.into_split();
+                    let mut lines = BufReader::new(read).lines();
+                    write.write_all(b"220 sink\r\n").await.unwrap();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let reply = match &line.to_ascii_uppercase()[..4.min(line.len())] {
+                            "DATA" => {
+                                write.write_all(b"354 go ahead\r\n").await.unwrap();
+                                let mut data = String::new();
+                                while let Ok(Some(line)) = lines.next_line().await {
+                                    if line == "." {
+                                        break;
+                                    }
+                                    data.push_str(&line);
+                                    data.push('\n');
+                                }
+                                tx.send(data).ok();
+                                "250 queued"
+                            }
+                            "QUIT" => {
+                                write.write_all(b"221 bye\r\n").await.ok();
+                                break;
+                            }
+                            _ => "250 ok",
+                        };
+                        write
+                            .write_all(format!("{reply}\r\n").as_bytes())
+                            .await
+                            .unwrap();
+                    }
+                });
+            }
+        });
+        (port, rx)
+    }
+
+    /// Content type and decoded body of every part of a multipart mail
+    fn parts(mail: &str) -> Vec<(String, String)> {
+        let boundary = mail
+            .split("boundary=\"")
+            .nth(1)
+            .and_then(|b| b.split('"').next())
+            .unwrap();
+        mail.split(&format!("--{boundary}"))
+            .skip(1)
+            .filter(|part| !part.starts_with("--"))
+            .filter_map(|part| {
+                let (head, body) = part.split_once("\n\n")?;
+                let header = |name: &str| {
+                    head.lines()
+                        .find_map(|l| l.strip_prefix(name))
+                        .map(str::trim)
+                        .unwrap_or_default()
+                };
+                let body = match header("Content-Transfer-Encoding:") {
+                    "base64" => STANDARD.decode(body.replace('\n', "")).unwrap(),
+                    "quoted-printable" => quoted_printable::decode(
+                        body.replace('\n', "\r\n"),
+                        quoted_printable::ParseMode::Robust,
+                    )
+                    .unwrap(),
+                    _ => body.as_bytes().to_vec(),
+                };
+                let content_type = header("Content-Type:").split(';').next().unwrap();
+                Some((content_type.to_owned(), String::from_utf8(body).unwrap()))
+            })
+            .collect()
+    }
+
+    fn mailer(port: u16) -> Mailer {
+        let config: SmtpConfig = toml::from_str(&format!(
+            r#"
+            host = "127.0.0.1"
+            port = {port}
+            tls = "none"
+            from = "Stundenplan <untis@example.org>"
+            "#
+        ))
+        .unwrap();
+        Mailer::new(&config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn sends_plain_and_html() {
+        let (port, mut mails) = sink().await;
+        let (tenant, _dir) = tenant(week());
+        let sent = send(&tenant, &mailer(port), &subscriber(&["MA1"], false), day()).await;
+        assert_eq!(sent, Ok(true));
+
+        let mail = mails.recv().await.unwrap();
+        assert!(mail.contains("To: eltern@example.org"));
+        assert!(mail.contains("Content-Type: multipart/alternative"));
+        let parts = parts(&mail);
+        let parts = parts
+            .iter()
+            .map(|(t, b)| (t.as_str(), b))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(parts.len(), 2);
+        let plain = parts["text/plain"];
+        assert!(plain.starts_with("Stundenplan für Dienstag, 20.10.2026"));
+        assert!(plain.contains("08:00 Mathe <LK>: fällt aus"));
+        assert!(plain.contains("Mathe <LK>: <b>Seite 5</b> & 6"));
+        let html = parts["text/html"];
+        assert!(html.contains("<td><b>Mathe &lt;LK&gt;</b></td><td>fällt aus</td>"));
+        assert!(
+            html.contains("<li><b>Mathe &lt;LK&gt;</b>: &lt;b&gt;Seite 5&lt;/b&gt; &amp; 6</li>")
+        );
+        assert!(!html.contains("<LK>"));
+    }
+
+    #[tokio::test]
+    async fn empty_digests_are_only_sent_on_request() {
+        let (port, mut mails) = sink().await;
+        let (tenant, _dir) = tenant(BTreeMap::new());
+        let mailer = mailer(port);
+        let sent = send(&tenant, &mailer, &subscriber(&["MA1"], false), day()).await;
+        assert_eq!(sent, Ok(false));
+        assert!(mails.try_recv().is_err());
+
+        let sent = send(&tenant, &mailer, &subscriber(&["MA1"], true), day()).await;
+        assert_eq!(sent, Ok(true));
+        let mail = mails.recv().await.unwrap();
+        let parts = parts(&mail);
+        assert!(parts
+            .iter()
+            .any(|(_, body)| body.contains("Keine Änderungen.")));
+    }
+}
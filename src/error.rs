@@ -0,0 +1,135 @@
+use std::{fmt::Display, time::Duration};
+
+use chrono::DateTime;
+use reqwest::{header::RETRY_AFTER, Response, StatusCode};
+
+use crate::{auth::AuthError, fetch::unix_now};
+
+/// Why talking to WebUntis failed
+#[derive(Debug)]
+pub enum Error {
+    Network(reqwest::Error),
+    /// The login failed, see `AuthError` for redirects to unexpected pages
+    Auth(AuthError),
+    /// Logins are held back after rejected credentials
+    LoginBlocked(String),
+    /// WebUntis handed out no usable token
+    Token(String),
+    /// A response did not match the expected JSON schema
+    Schema(serde_json::Error),
+    /// Upstream answered 429, possibly saying when to come back
+    RateLimited(Option<Duration>),
+    /// Upstream answered with a 5xx status
+    Upstream(StatusCode),
+    /// Upstream did not accept the session any more
+    SessionRejected(StatusCode),
+    /// Any other unexpected status
+    Status(StatusCode),
+    /// The response exceeded `fetch.max_response_kb`
+    TooLarge(usize),
+    /// The circuit breaker refuses requests until the given unix timestamp
+    CircuitOpen(u64),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Network(e) => write!(f, "network error: {e}"),
+            Error::Auth(e) => write!(f, "login failed: {e}"),
+            Error::LoginBlocked(reason) => write!(f, "login held back: {reason}"),
+            Error::Token(reason) => write!(f, "no usable token: {reason}"),
+            Error::Schema(e) => write!(f, "unexpected response: {e}"),
+            Error::RateLimited(Some(after)) => {
+                write!(f, "rate limited, retry after {}s", after.as_secs())
+            }
+            Error::RateLimited(None) => write!(f, "rate limited"),
+            Error::Upstream(status) => write!(f, "upstream failed with {status}"),
+            Error::SessionRejected(status) => write!(f, "session rejected with {status}"),
+            Error::Status(status) => write!(f, "upstream answered {status}"),
+            Error::TooLarge(len) => write!(f, "response too large ({} KiB)", len / 1024),
+            Error::CircuitOpen(until) => write!(f, "circuit breaker open until {until}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Network(e)
+    }
+}
+
+impl From<AuthError> for Error {
+    fn from(e: AuthError) -> Self {
+        Error::Auth(e)
+    }
+}
+
+impl Error {
+    /// Short label for logs, metrics and the status endpoint
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Network(_) | Error::Auth(AuthError::Network(_)) => "network",
+            Error::Auth(AuthError::UnexpectedPage(_) | AuthError::UnexpectedHost(_)) => {
+                "auth_redirect"
+            }
+            Error::Auth(AuthError::NoToken) | Error::Token(_) => "token",
+            Error::Auth(_) => "auth",
+            Error::LoginBlocked(_) => "login_blocked",
+            Error::Schema(_) => "schema",
+            Error::RateLimited(_) => "rate_limited",
+            Error::Upstream(_) => "upstream",
+            Error::SessionRejected(_) => "session_rejected",
+            Error::Status(_) => "status",
+            Error::TooLarge(_) => "too_large",
+            Error::CircuitOpen(_) => "circuit_open",
+        }
+    }
+
+    /// Error for a response with an unsuccessful status, `None` if it succeeded
+    pub fn from_response(res: &Response) -> Option<Self> {
+        let status = res.status();
+        Some(match status {
+            s if s.is_success() => return None,
+            StatusCode::TOO_MANY_REQUESTS => Error::RateLimited(
+                res.headers()
+                    .get(RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(retry_after),
+            ),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Error::SessionRejected(status),
+            s if s.is_server_error() => Error::Upstream(status),
+            _ => Error::Status(status),
+        })
+    }
+
+    /// Whether fetching a smaller range might succeed
+    pub fn is_splittable(&self) -> bool {
+        match self {
+            // 502 and 503 mean upstream is down, smaller ranges would only add load
+            Error::Upstream(status) => {
+                matches!(
+                    *status,
+                    StatusCode::INTERNAL_SERVER_ERROR | StatusCode::GATEWAY_TIMEOUT
+                )
+            }
+            Error::Status(status) => *status == StatusCode::PAYLOAD_TOO_LARGE,
+            Error::Network(_) | Error::Schema(_) | Error::TooLarge(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Parses a `Retry-After` value, either seconds or an HTTP date. Dates in the past
+/// mean right away.
+fn retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = DateTime::parse_from_rfc2822(value).ok()?.timestamp();
+    Some(Duration::from_secs(
+        u64::try_from(at).unwrap_or(0).saturating_sub(unix_now()),
+    ))
+}
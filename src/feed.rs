@@ -0,0 +1,180 @@
+use chrono::DateTime;
+
+use crate::{
+    changes::{Change, ChangeKind},
+    fetch::unix_now,
+    tenant::Tenant,
+};
+
+/// Entries per feed, the newest are kept
+const MAX_ENTRIES: usize = 200;
+
+/// Atom feed of the changes to the given courses of the default grade, newest first.
+/// Lessons without a subject are always included, like in `/ics`. `url` is the one
+/// the feed was requested with, its entries link to the calendar next to it.
+pub fn atom(tenant: &Tenant, courses: &[&str], url: &str) -> String {
+    let element = tenant.default_key();
+    let mut changes = tenant
+        .changes
+        .since(0)
+        .into_iter()
+        .filter(|c| c.element == element)
+        .filter(|c| c.subject.as_deref().is_none_or(|s| courses.contains(&s)))
+        .collect::<Vec<_>>();
+    changes.reverse();
+    changes.truncate(MAX_ENTRIES);
+
+    let id = format!("urn:untis:{}:{}", tenant.id(), courses.join(","));
+    let updated = changes.first().map_or(unix_now(), |c| c.at);
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let calendar = format!("{}/ics?{query}", path.strip_suffix("/feed").unwrap_or(path));
+    let mut feed = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <feed xmlns=\"http://www.w3.org/2005/Atom\">\n\
+         <id>{}</id>\n<title>Stundenplanänderungen {}</title>\n<updated>{}</updated>\n\
+         <author><name>{}</name></author>\n<link rel=\"self\" href=\"{}\"/>\n",
+        escape(&id),
+        escape(&courses.join(", ")),
+        timestamp(updated),
+        escape(tenant.id()),
+        escape(url),
+    );
+    for change in &changes {
+        let subject = change.subject.as_deref().unwrap_or_default();
+        let name = tenant.alias.get(subject).map_or(subject, String::as_str);
+        feed.push_str(&format!(
+            "<entry>\n<id>{id}:{}:{}:{}</id>\n<title>{}</title>\n<updated>{}</updated>\n<link rel=\"related\" type=\"text/calendar\" href=\"{}\"/>\n<content type=\"text\">{}</content>\n</entry>\n",
+            change.lesson,
+            change.at,
+            change.kind.name(),
+            escape(&title(change, name)),
+            timestamp(change.at),
+            escape(&calendar),
+            escape(&content(change)),
+        ));
+    }
+    feed.push_str("</feed>\n");
+    feed
+}
+
+fn title(change: &Change, name: &str) -> String {
+    let when = change.start.replace('T', " ");
+    match &change.kind {
+        ChangeKind::Added => format!("{name} am {when} zusätzlich"),
+        ChangeKind::Removed => format!("{name} am {when} gestrichen"),
+        ChangeKind::Cancelled => format!("{name} am {when} fällt aus"),
+        ChangeKind::Reinstated => format!("{name} am {when} findet wieder statt"),
+        ChangeKind::Moved { from, .. } => {
+            format!(
+                "{name} verschoben von {} auf {when}",
+                from.replace('T', " ")
+            )
+        }
+        ChangeKind::RoomChanged { to, .. } => {
+            format!("{name} am {when} in Raum {}", to.join(", "))
+        }
+        ChangeKind::Substitution { to, .. } => {
+            format!("{name} am {when} Vertretung durch {}", to.join(", "))
+        }
+        ChangeKind::Homework { .. } => format!("{name} am {when} neue Hausaufgabe"),
+    }
+}
+
+fn content(change: &Change) -> String {
+    match &change.kind {
+        ChangeKind::RoomChanged { from, to } | ChangeKind::Substitution { from, to } => {
+            format!("{} → {}", from.join(", "), to.join(", "))
+        }
+        ChangeKind::Moved { from, to } => format!("{from} → {to}"),
+        ChangeKind::Homework { text } => text.clone(),
+        _ => format!("{} bis {}", change.start, change.end),
+    }
+}
+
+fn timestamp(unix: u64) -> String {
+    DateTime::from_timestamp(unix as i64, 0)
+        .unwrap_or_default()
+        .to_rfc3339()
+}
+
+/// Escapes text for XML and HTML content and attribute values
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+    use crate::testutil;
+
+    fn change(element: isize, lesson: i64, subject: Option<&str>) -> Change {
+        Change {
+            at: 0,
+            element,
+            day: NaiveDate::from_ymd_opt(2026, 10, 19).unwrap(),
+            lesson,
+            subject: subject.map(str::to_owned),
+            start: "2026-10-19T08:00".to_owned(),
+            end: "2026-10-19T08:45".to_owned(),
+            kind: ChangeKind::Cancelled,
+        }
+    }
+
+    /// Lesson ids of the entries in the order of the feed
+    fn lessons(feed: &str) -> Vec<i64> {
+        feed.split("<entry>")
+            .skip(1)
+            .map(|e| {
+                let id = &e[e.find("<id>").unwrap() + 4..e.find("</id>").unwrap()];
+                id.split(':').nth(4).unwrap().parse().unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn feed_of_the_default_grade() {
+        let (tenant, _dir) = testutil::tenant("http://127.0.0.1:1", "");
+        let element = tenant.default_key();
+        tenant.changes.push(vec![
+            change(element, 1, Some("MA1")),
+            change(element, 2, None),
+            change(element, 3, Some("DE2")),
+            change(element - 1, 4, Some("MA1")),
+        ]);
+        tenant.changes.push(vec![change(element, 5, Some("MA1"))]);
+
+        let url = "https://example.org/s/test/feed?MA1";
+        let feed = atom(&tenant, &["MA1"], url);
+        assert_eq!(lessons(&feed), vec![5, 2, 1]);
+        assert!(feed.contains("<author><name>test</name></author>"));
+        assert!(feed.contains(r#"<link rel="self" href="https://example.org/s/test/feed?MA1"/>"#));
+        assert!(feed.contains(r#"href="https://example.org/s/test/ics?MA1"/>"#));
+    }
+
+    #[test]
+    fn keeps_the_newest_entries() {
+        let (tenant, _dir) = testutil::tenant("http://127.0.0.1:1", "");
+        let element = tenant.default_key();
+        for lesson in 0..MAX_ENTRIES as i64 + 50 {
+            tenant.changes.push(vec![change(element, lesson, None)]);
+        }
+        let lessons = lessons(&atom(&tenant, &[], "http://localhost/feed"));
+        assert_eq!(lessons.len(), MAX_ENTRIES);
+        assert_eq!(lessons[0], MAX_ENTRIES as i64 + 49);
+        assert_eq!(lessons[MAX_ENTRIES - 1], 50);
+    }
+
+    #[test]
+    fn escapes_markup() {
+        assert_eq!(
+            escape(r#"<b>"M&M's"</b>"#),
+            "&lt;b&gt;&quot;M&amp;M's&quot;&lt;/b&gt;"
+        );
+        assert_eq!(escape("Raum 1.02 ➕"), "Raum 1.02 ➕");
+    }
+}
@@ -1,122 +1,272 @@
 use std::{
-    collections::{HashMap, HashSet},
-    sync::Arc,
-    time::Duration,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    sync::atomic::Ordering,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use chrono::{Datelike, Days, Local, NaiveDate, NaiveTime};
-use governor::{DefaultDirectRateLimiter, Jitter};
+use chrono::{Datelike, Days, NaiveDate, NaiveTime};
+use governor::Jitter;
 use ics::{
     properties::{Description, DtEnd, DtStart, Summary},
     Event,
 };
 use reqwest::{Client, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
 
 use crate::{
     create_timestamp,
     definitions::{CalendarEntry, Root, Status},
-    login, TimeTableData, ALIAS,
+    error::Error,
+    session::Session,
+    tenant::Tenant,
+    TimeTableData,
 };
 
-const NEGATIVE_OFFSET: u64 = 14;
-const POSITIVE_OFFSET: usize = 70;
+/// Spreads the requests of tasks that become due at the same time
+#[cfg(not(test))]
+const REQUEST_JITTER: Duration = Duration::from_secs(3);
+#[cfg(test)]
+const REQUEST_JITTER: Duration = Duration::ZERO;
 
+/// Outcome of fetching one day
+pub enum DayResult {
+    Fetched(Vec<CalendarEntry>),
+    Failed(String),
+}
+
+/// Freshness of one day of an element
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct DayStatus {
+    /// Unix timestamp of the last successful fetch
+    pub fetched_at: Option<u64>,
+    /// Set while fetches of the day fail, its last good lessons are kept meanwhile
+    pub failing_since: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// Fetches the days from `first` to `last`. Every weekday is part of the result,
+/// an error means there was no usable session at all.
 pub async fn fetch(
     e_id: isize,
     client: &Client,
-    limiter: &Arc<DefaultDirectRateLimiter>,
-    cookies: String,
-) -> Option<(TimeTableData, String)> {
-    let (token, cookies) = login(None, None, Some(cookies)).await?;
+    tenant: &Tenant,
+    first: NaiveDate,
+    last: NaiveDate,
+) -> Result<BTreeMap<NaiveDate, DayResult>, Error> {
+    let school = &tenant.school;
+    if let Some(until) = tenant.breaker.blocked_until() {
+        return Err(Error::CircuitOpen(until));
+    }
+    let Session { token, cookies, .. } = tenant.session().await?;
+    let detail_url = school.untis("/WebUntis/api/rest/view/v2/calendar-entry/detail");
     // let client = Client::new();
+    let timeout = Duration::from_secs(school.fetch.timeout);
     let req_builder = client
-        .get("https://nessa.webuntis.com/WebUntis/api/rest/view/v2/calendar-entry/detail")
+        .get(&detail_url)
+        .timeout(timeout)
         .bearer_auth(token.clone())
         .header("Cookie", cookies.clone());
 
-    let starting_day = Local::now()
-        .date_naive()
-        .week(chrono::Weekday::Mon)
-        .first_day()
-        - Days::new(NEGATIVE_OFFSET);
-
-    let days = starting_day
-        .iter_days()
-        .take(NEGATIVE_OFFSET as usize + POSITIVE_OFFSET)
-        .filter(|d| !matches!(d.weekday(), chrono::Weekday::Sat | chrono::Weekday::Sun));
+    let batch = tenant.batch_days.load(Ordering::Relaxed).max(1);
+    let mut ranges = VecDeque::new();
+    let mut day = first;
+    while day <= last {
+        let end = (day + Days::new(batch as u64 - 1)).min(last);
+        ranges.push_back((day, end));
+        day = end + Days::new(1);
+    }
 
-    let mut ttd = TimeTableData::default();
-    let jitter = Jitter::up_to(Duration::from_secs(3));
-    for day in days {
-        limiter.until_ready_with_jitter(jitter).await;
+    let mut days = BTreeMap::new();
+    let mut seen = HashSet::new();
+    let mut days_failed = BTreeMap::new();
+    let mut failed_days = None;
+    let jitter = Jitter::up_to(REQUEST_JITTER);
+    while let Some((start, end)) = ranges.pop_front() {
+        if only_weekend(start, end) {
+            continue;
+        }
+        tenant.limiter().until_ready_with_jitter(jitter).await;
         let req = req_builder.try_clone().unwrap_or_else(|| {
             client
-                .get("https://nessa.webuntis.com/WebUntis/api/rest/view/v2/calendar-entry/detail")
+                .get(&detail_url)
+                .timeout(timeout)
                 .bearer_auth(token.clone())
                 .header("Cookie", cookies.clone())
         });
-        if let Some(t) = fetch_for_day(day, req, e_id).await {
-            combine_ttd(&mut ttd, t)
-        };
-    }
-
-    Some((ttd, cookies))
-}
-
-fn combine_ttd(ttd1: &mut TimeTableData, ttd2: TimeTableData) {
-    for (subj, mut v) in ttd2.blocks {
-        match ttd1.blocks.get_mut(&subj) {
-            Some(vec) => vec.append(&mut v),
-            None => {
-                ttd1.blocks.insert(subj, v);
+        let max_bytes = school.fetch.max_response_kb * 1024;
+        if let Err(e) = tenant.breaker.acquire() {
+            // the remaining days keep their old lessons until the breaker closes
+            for (start, end) in std::iter::once((start, end)).chain(ranges.drain(..)) {
+                for day in weekdays(start, end) {
+                    days_failed.insert(day, DayResult::Failed(e.to_string()));
+                }
             }
+            break;
         }
-    }
-    for (subj, v) in ttd2.tasks {
-        match ttd1.tasks.get_mut(&subj) {
-            Some(set) => set.extend(v.into_iter()),
-            None => {
-                ttd1.tasks.insert(subj, v);
+        tenant.metrics.request();
+        let result = fetch_range(start, end, req, e_id, max_bytes).await;
+        match &result {
+            Ok(_) => tenant.breaker.success(),
+            Err(e) => tenant.breaker.failure(e),
+        }
+        match result {
+            Ok(data) => {
+                for day in start.iter_days().take_while(|d| *d <= end) {
+                    days.insert(day, DayResult::Fetched(Vec::new()));
+                }
+                for entry in data.calendar_entries {
+                    if !seen.insert(entry.id) {
+                        continue;
+                    }
+                    let day = entry_day(&entry).unwrap_or(start);
+                    // lessons of days outside the range are left to the fetch of their day
+                    if let Some(DayResult::Fetched(entries)) = days.get_mut(&day) {
+                        entries.push(entry);
+                    }
+                }
+            }
+            Err(e @ Error::SessionRejected(_)) => {
+                warn!(
+                    kind = e.kind(),
+                    "Session abgelehnt ({e}), melde mich beim nächsten Durchlauf neu an"
+                );
+                tenant.metrics.error(&e);
+                tenant.sessions.invalidate(&token).await;
+                return Err(e);
+            }
+            Err(e) => {
+                tenant.metrics.error(&e);
+                let days = (end - start).num_days() as u32 + 1;
+                if days == 1 || !e.is_splittable() {
+                    warn!(kind = e.kind(), "{start} bis {end} übersprungen: {e}");
+                    for day in weekdays(start, end) {
+                        days_failed.insert(day, DayResult::Failed(e.to_string()));
+                    }
+                    continue;
+                }
+                debug!(kind = e.kind(), "{start} bis {end} wird geteilt: {e}");
+                failed_days = Some(failed_days.unwrap_or(days).min(days));
+                let mid = start + Days::new(days as u64 / 2);
+                ranges.push_front((mid, end));
+                ranges.push_front((start, mid - Days::new(1)));
             }
         }
     }
-    for (teach, subj) in ttd2.teachers {
-        match ttd1.teachers.get_mut(&teach) {
-            Some(set) => {
-                set.extend(subj);
+    // start the next cycle with the range size that worked, or grow back towards the limit
+    let next = match failed_days {
+        Some(days) => (days / 2).max(1),
+        None => (batch * 2).min(school.fetch.batch_days),
+    };
+    if next != batch {
+        debug!("Abfragen jetzt mit {next} Tagen statt {batch}");
+        tenant.batch_days.store(next, Ordering::Relaxed);
+    }
+
+    days.extend(days_failed);
+    Ok(days)
+}
+
+/// Applies the results of a fetch. Failed days keep their lessons and are marked as
+/// failing until a later fetch succeeds.
+pub fn apply(
+    entries: &mut BTreeMap<NaiveDate, Vec<CalendarEntry>>,
+    status: &mut BTreeMap<NaiveDate, DayStatus>,
+    results: BTreeMap<NaiveDate, DayResult>,
+) {
+    let now = unix_now();
+    for (day, result) in results {
+        let day_status = status.entry(day).or_default();
+        match result {
+            DayResult::Fetched(lessons) => {
+                entries.insert(day, lessons);
+                *day_status = DayStatus {
+                    fetched_at: Some(now),
+                    ..Default::default()
+                };
             }
-            None => {
-                ttd1.teachers.insert(teach, HashSet::from_iter(subj));
+            DayResult::Failed(error) => {
+                day_status.failing_since.get_or_insert(now);
+                day_status.error = Some(error);
             }
         }
     }
 }
 
-async fn fetch_for_day(
-    day: NaiveDate,
+/// Marks all weekdays from `first` to `last` as failed
+pub fn fail_range(
+    first: NaiveDate,
+    last: NaiveDate,
+    error: &str,
+) -> BTreeMap<NaiveDate, DayResult> {
+    weekdays(first, last)
+        .map(|day| (day, DayResult::Failed(error.to_owned())))
+        .collect()
+}
+
+fn weekdays(first: NaiveDate, last: NaiveDate) -> impl Iterator<Item = NaiveDate> {
+    first
+        .iter_days()
+        .take_while(move |d| *d <= last)
+        .filter(|d| !matches!(d.weekday(), chrono::Weekday::Sat | chrono::Weekday::Sun))
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn only_weekend(start: NaiveDate, end: NaiveDate) -> bool {
+    weekdays(start, end).next().is_none()
+}
+
+async fn fetch_range(
+    start: NaiveDate,
+    end: NaiveDate,
     req_builder: RequestBuilder,
     e_id: isize,
-) -> Option<TimeTableData> {
-    let mut ttd = TimeTableData::default();
-
+    max_bytes: usize,
+) -> Result<Root, Error> {
     let res = req_builder
-        .query(&generate_params_for_date(day, e_id))
+        .query(&generate_params_for_range(start, end, e_id))
         .send()
-        .await
-        .ok()?;
-    // println!("{:?}", res);
+        .await?;
+    if let Some(e) = Error::from_response(&res) {
+        return Err(e);
+    }
+    let body = res.bytes().await?;
+    if body.len() > max_bytes {
+        return Err(Error::TooLarge(body.len()));
+    }
+    serde_json::from_slice::<Root>(&body).map_err(Error::Schema)
+}
 
-    let data = res.json::<Root>().await.unwrap_or_default();
+/// Day on which a lesson starts, read from its local start time
+fn entry_day(entry: &CalendarEntry) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(entry.start_date_time.get(..10)?, "%Y-%m-%d").ok()
+}
 
-    ttd.tasks = data
-        .calendar_entries
-        .iter()
-        .filter_map(|entry| create_hw_events(entry))
-        .collect::<HashMap<_, _>>();
+/// Builds the calendar data of an element from its lessons by day
+pub fn build_ttd(
+    entries: BTreeMap<NaiveDate, Vec<CalendarEntry>>,
+    tenant: &Tenant,
+) -> TimeTableData {
+    let mut ttd = TimeTableData {
+        tasks: entries
+            .values()
+            .flatten()
+            .filter_map(create_hw_events)
+            .fold(HashMap::new(), |mut tasks, (subj, hw)| {
+                tasks.entry(subj).or_insert_with(HashSet::new).extend(hw);
+                tasks
+            }),
+        ..Default::default()
+    };
 
-    ttd.blocks = HashMap::new();
-    data.calendar_entries.into_iter().for_each(|entry| {
-        let (subj, teacher, ev) = create_block_event(entry);
+    entries.values().flatten().for_each(|entry| {
+        let (subj, teacher, ev) = create_block_event(entry, tenant);
         match ttd.teachers.get_mut(&teacher) {
             Some(set) => {
                 set.insert(subj.clone());
@@ -134,7 +284,8 @@ async fn fetch_for_day(
         }
     });
 
-    Some(ttd)
+    ttd.entries = entries;
+    ttd
 }
 
 fn create_hw_events(entry: &CalendarEntry) -> Option<(String, HashSet<Event<'static>>)> {
@@ -168,7 +319,7 @@ fn create_hw_events(entry: &CalendarEntry) -> Option<(String, HashSet<Event<'sta
     Some((subject, hw))
 }
 
-fn create_block_event(entry: CalendarEntry) -> (String, String, Event<'static>) {
+fn create_block_event(entry: &CalendarEntry, tenant: &Tenant) -> (String, String, Event<'static>) {
     let id = entry.id.to_string();
     let dtstamp = chrono::Local::now().format("%Y%m%dT%H%M%SZ").to_string();
     let mut ev = Event::new(id, dtstamp);
@@ -178,10 +329,10 @@ fn create_block_event(entry: CalendarEntry) -> (String, String, Event<'static>)
         _ => ics::properties::Status::confirmed(),
     };
     ev.push(status);
-    ev.push(generate_summary(entry.clone()));
-    ev.push(generate_description(&entry));
-    ev.push(location(&entry));
-    add_timestamps(&mut ev, &entry);
+    ev.push(generate_summary(entry.clone(), tenant));
+    ev.push(generate_description(entry));
+    ev.push(location(entry, tenant));
+    add_timestamps(&mut ev, entry);
     let teacher_name = entry
         .teachers
         .iter()
@@ -191,23 +342,25 @@ fn create_block_event(entry: CalendarEntry) -> (String, String, Event<'static>)
     (
         entry
             .subject
-            .map_or("default".to_owned(), |s| s.display_name),
+            .as_ref()
+            .map_or("default".to_owned(), |s| s.display_name.clone()),
         teacher_name,
         ev.clone(),
     )
 }
 
-fn location(entry: &CalendarEntry) -> ics::properties::Location<'static> {
+fn location(entry: &CalendarEntry, tenant: &Tenant) -> ics::properties::Location<'static> {
     let l_alias = "l".to_owned()
         + &entry
             .subject
             .clone()
             .map_or("default".to_owned(), |s| s.display_name.to_owned())
             .clone();
-    let location = ALIAS
+    let location = tenant
+        .alias
         .get_key_value(&l_alias)
         .map(|(_, val)| val.clone())
-        .unwrap_or("Am Marktplatz 18, 28832 Achim, Deutschland".to_owned());
+        .unwrap_or(tenant.school.location.clone());
     ics::properties::Location::new(location)
 }
 
@@ -235,8 +388,9 @@ fn generate_description(entry: &CalendarEntry) -> ics::properties::Description<'
     ))
 }
 
-fn generate_summary(entry: CalendarEntry) -> ics::properties::Summary<'static> {
-    let name = ALIAS
+fn generate_summary(entry: CalendarEntry, tenant: &Tenant) -> ics::properties::Summary<'static> {
+    let name = tenant
+        .alias
         .get_key_value(
             &entry
                 .subject
@@ -247,8 +401,9 @@ fn generate_summary(entry: CalendarEntry) -> ics::properties::Summary<'static> {
         .unwrap_or(entry.subject.clone().map_or("".to_owned(), |s| s.long_name));
     let room = entry
         .rooms
-        .into_iter()
+        .iter()
         .find(|el| el.status != Status::Removed)
+        .cloned()
         .unwrap_or_default();
 
     let teacher_name = entry
@@ -258,15 +413,28 @@ fn generate_summary(entry: CalendarEntry) -> ics::properties::Summary<'static> {
         .map(|el| el.long_name.clone())
         .unwrap_or_default();
     let mut sum = format!("{} - {} - {}", name, room.display_name, teacher_name);
-    if room.status == Status::Substitution {
+    if is_substitution(&entry) {
         sum = "🔄 ".to_owned() + &sum;
     }
-    if entry.type_field == crate::definitions::Type::AddiotionalPeriod {
+    if is_additional(&entry) {
         sum = "➕ ".to_owned() + &sum;
     }
     ics::properties::Summary::new(sum)
 }
 
+/// Whether the lesson takes place in a substitute room
+pub fn is_substitution(entry: &CalendarEntry) -> bool {
+    entry
+        .rooms
+        .iter()
+        .find(|el| el.status != Status::Removed)
+        .is_some_and(|room| room.status == Status::Substitution)
+}
+
+pub fn is_additional(entry: &CalendarEntry) -> bool {
+    entry.type_field == crate::definitions::Type::AddiotionalPeriod
+}
+
 fn add_timestamps(event: &mut Event<'_>, entry: &CalendarEntry) {
     event.push(DtStart::new(
         create_timestamp(&entry.start_date_time).unwrap_or_default(),
@@ -276,7 +444,11 @@ fn add_timestamps(event: &mut Event<'_>, entry: &CalendarEntry) {
     ));
 }
 
-fn generate_params_for_date(date: NaiveDate, e_id: isize) -> HashMap<String, String> {
+fn generate_params_for_range(
+    start: NaiveDate,
+    end: NaiveDate,
+    e_id: isize,
+) -> HashMap<String, String> {
     let mut map = HashMap::new();
 
     map.insert("elementId".to_owned(), e_id.abs().to_string());
@@ -286,13 +458,133 @@ fn generate_params_for_date(date: NaiveDate, e_id: isize) -> HashMap<String, Str
         map.insert("elementType".to_owned(), "5".to_owned());
     }
 
-    let start_time = date.and_time(NaiveTime::MIN);
+    let start_time = start.and_time(NaiveTime::MIN);
     let start = start_time.to_string().replace(" ", "T");
     map.insert("startDateTime".to_owned(), start);
 
-    let end_time = date.and_time(NaiveTime::from_hms_opt(23, 59, 59).unwrap());
+    let end_time = end.and_time(NaiveTime::from_hms_opt(23, 59, 59).unwrap());
     let end = end_time.to_string().replace(" ", "T");
     map.insert("endDateTime".to_owned(), end);
 
     map
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc, Mutex,
+    };
+
+    use hyper::StatusCode;
+    use reqwest::Url;
+
+    use super::*;
+    use crate::testutil::{serve, status, tenant, webuntis};
+
+    type Requests = Arc<Mutex<Vec<(NaiveDate, NaiveDate)>>>;
+
+    /// WebUntis that answers ranges longer than `max_days` with 413 and records the
+    /// ranges it was asked for
+    async fn untis(max_days: Arc<AtomicI64>) -> (String, Requests) {
+        let requests = Requests::default();
+        let recorded = requests.clone();
+        let addr = serve(move |req| {
+            if let Some(res) = webuntis(&req) {
+                return res;
+            }
+            let url = Url::parse(&format!("http://untis{}", req.uri())).unwrap();
+            let day = |name: &str| {
+                let (_, value) = url.query_pairs().find(|(k, _)| k == name).unwrap();
+                NaiveDate::parse_from_str(&value[..10], "%Y-%m-%d").unwrap()
+            };
+            let (start, end) = (day("startDateTime"), day("endDateTime"));
+            recorded.lock().unwrap().push((start, end));
+            if (end - start).num_days() + 1 > max_days.load(Ordering::Relaxed) {
+                return status(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+            hyper::Response::new(r#"{"calendarEntries":[]}"#.to_owned())
+        })
+        .await;
+        (format!("http://127.0.0.1:{}", addr.port()), requests)
+    }
+
+    fn date(month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2026, month, day).unwrap()
+    }
+
+    #[tokio::test]
+    async fn splits_failing_ranges_and_shrinks_batch() {
+        let (url, requests) = untis(Arc::new(AtomicI64::new(4))).await;
+        let (tenant, _dir) = tenant(&url, "");
+        let (first, last) = (date(10, 19), date(11, 1));
+        let results = fetch(-1, &Client::new(), &tenant, first, last)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *requests.lock().unwrap(),
+            [
+                (first, last),
+                (date(10, 19), date(10, 25)),
+                (date(10, 19), date(10, 21)),
+                (date(10, 22), date(10, 25)),
+                (date(10, 26), date(11, 1)),
+                (date(10, 26), date(10, 28)),
+                (date(10, 29), date(11, 1)),
+            ]
+        );
+        assert_eq!(results.len(), 14);
+        assert!(results
+            .values()
+            .all(|r| matches!(r, DayResult::Fetched(lessons) if lessons.is_empty())));
+        // the smallest failing range had 7 days, the next cycle starts below it
+        assert_eq!(tenant.batch_days.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn single_days_that_fail_are_marked_failed() {
+        let (url, requests) = untis(Arc::new(AtomicI64::new(0))).await;
+        let (tenant, _dir) = tenant(&url, "[fetch]\nbatch_days = 2");
+        let results = fetch(-1, &Client::new(), &tenant, date(10, 23), date(10, 26))
+            .await
+            .unwrap();
+
+        // ranges of only weekend days are never asked for
+        assert_eq!(
+            *requests.lock().unwrap(),
+            [
+                (date(10, 23), date(10, 24)),
+                (date(10, 23), date(10, 23)),
+                (date(10, 25), date(10, 26)),
+                (date(10, 26), date(10, 26)),
+            ]
+        );
+        assert_eq!(
+            results.keys().copied().collect::<Vec<_>>(),
+            [date(10, 23), date(10, 26)]
+        );
+        assert!(results.values().all(|r| matches!(r, DayResult::Failed(_))));
+        assert_eq!(tenant.batch_days.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn batch_grows_back_to_the_limit() {
+        let max_days = Arc::new(AtomicI64::new(4));
+        let (url, requests) = untis(max_days.clone()).await;
+        let (tenant, _dir) = tenant(&url, "");
+        let (first, last) = (date(10, 19), date(11, 1));
+        let client = Client::new();
+        fetch(-1, &client, &tenant, first, last).await.unwrap();
+        assert_eq!(tenant.batch_days.load(Ordering::Relaxed), 3);
+
+        max_days.store(i64::MAX, Ordering::Relaxed);
+        for expected in [6, 12, 14, 14] {
+            requests.lock().unwrap().clear();
+            fetch(-1, &client, &tenant, first, last).await.unwrap();
+            assert_eq!(tenant.batch_days.load(Ordering::Relaxed), expected);
+        }
+        // at the limit the whole window is a single request
+        assert_eq!(*requests.lock().unwrap(), [(first, last)]);
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    path::Path,
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+};
+
+use chrono::NaiveDate;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{definitions::CalendarEntry, fetch::unix_now};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS lesson_versions (
+    school TEXT NOT NULL,
+    element INTEGER NOT NULL,
+    id INTEGER NOT NULL,
+    version INTEGER NOT NULL,
+    day TEXT NOT NULL,
+    data TEXT NOT NULL,
+    first_seen INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL,
+    PRIMARY KEY (school, element, id, version)
+);
+CREATE INDEX IF NOT EXISTS lesson_versions_day ON lesson_versions (school, day);
+";
+
+#[derive(Serialize)]
+pub struct LessonVersion {
+    pub element: isize,
+    pub version: u32,
+    pub day: NaiveDate,
+    /// Unix timestamps of the first and last fetch that returned this version
+    pub first_seen: u64,
+    pub last_seen: u64,
+    pub entry: serde_json::Value,
+}
+
+/// Lessons of freshly fetched days on their way to the writer thread
+struct Record {
+    school: String,
+    element: isize,
+    /// Unix timestamp of the fetch
+    at: u64,
+    /// Day, id and JSON of every lesson
+    lessons: Vec<(NaiveDate, i64, String)>,
+}
+
+/// Every version of every lesson ever fetched, kept after the day left the window
+pub struct History {
+    conn: Arc<Mutex<Connection>>,
+    /// Records are written on their own thread in the order they were fetched, so
+    /// SQLite never blocks the runtime
+    writer: Sender<Record>,
+}
+
+impl History {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        let conn = Arc::new(Mutex::new(conn));
+        let (writer, records) = mpsc::channel::<Record>();
+        let db = conn.clone();
+        std::thread::spawn(move || {
+            for record in records {
+                if let Err(e) = write(&mut db.lock().unwrap(), &record) {
+                    warn!("Could not record lesson history of {}: {e}", record.element);
+                }
+            }
+        });
+        Ok(Self { conn, writer })
+    }
+
+    /// Queues the lessons of freshly fetched days. A lesson that did not change only
+    /// moves its `last_seen`, any difference starts a new version.
+    pub fn record<'a>(
+        &self,
+        school: &str,
+        element: isize,
+        days: impl IntoIterator<Item = (NaiveDate, &'a [CalendarEntry])>,
+    ) {
+        let lessons = days
+            .into_iter()
+            .flat_map(|(day, lessons)| {
+                lessons.iter().filter_map(move |lesson| {
+                    Some((day, lesson.id, serde_json::to_string(lesson).ok()?))
+                })
+            })
+            .collect();
+        let record = Record {
+            school: school.to_owned(),
+            element,
+            at: unix_now(),
+            lessons,
+        };
+        if self.writer.send(record).is_err() {
+            warn!("Lesson history of {element} not recorded, the writer stopped");
+        }
+    }
+
+    /// All versions of the lesson `id`, oldest first
+    pub fn versions(&self, school: &str, id: i64) -> rusqlite::Result<Vec<LessonVersion>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached(
+            "SELECT element, version, day, first_seen, last_seen, data FROM lesson_versions
+             WHERE school = ?1 AND id = ?2 ORDER BY first_seen, element, version",
+        )?;
+        let rows = stmt.query_map(params![school, id], |row| {
+            Ok(LessonVersion {
+                element: row.get(0)?,
+                version: row.get(1)?,
+                day: row.get::<_, String>(2)?.parse().unwrap_or_default(),
+                first_seen: row.get(3)?,
+                last_seen: row.get(4)?,
+                entry: serde_json::from_str(&row.get::<_, String>(5)?).unwrap_or_default(),
+            })
+        })?;
+        rows.collect()
+    }
+}
+
+fn write(conn: &mut Connection, record: &Record) -> rusqlite::Result<()> {
+    let Record {
+        school,
+        element,
+        at,
+        lessons,
+    } = record;
+    let tx = conn.transaction()?;
+    {
+        let mut latest = tx.prepare_cached(
+            "SELECT version, data FROM lesson_versions
+             WHERE school = ?1 AND element = ?2 AND id = ?3
+             ORDER BY version DESC LIMIT 1",
+        )?;
+        let mut touch = tx.prepare_cached(
+            "UPDATE lesson_versions SET last_seen = ?5
+             WHERE school = ?1 AND element = ?2 AND id = ?3 AND version = ?4",
+        )?;
+        let mut insert = tx.prepare_cached(
+            "INSERT INTO lesson_versions VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7)",
+        )?;
+        for (day, id, data) in lessons {
+            let previous = latest
+                .query_row(params![school, element, id], |row| {
+                    Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?))
+                })
+                .optional()?;
+            match previous {
+                Some((version, old)) if old == *data => {
+                    touch.execute(params![school, element, id, version, at])?;
+                }
+                previous => {
+                    let version = previous.map_or(1, |(v, _)| v + 1);
+                    insert.execute(params![
+                        school,
+                        element,
+                        id,
+                        version,
+                        day.to_string(),
+                        data,
+                        at
+                    ])?;
+                }
+            }
+        }
+    }
+    tx.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn lesson(info: &str) -> CalendarEntry {
+        CalendarEntry {
+            id: 42,
+            lesson_info: info.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn records_versions_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::open(&dir.path().join("history.db")).unwrap();
+        let day = NaiveDate::from_ymd_opt(2026, 10, 19).unwrap();
+        for info in ["A1", "A1", "B2", "A1"] {
+            history.record("gam", -5, [(day, &[lesson(info)][..])]);
+        }
+
+        let mut versions = Vec::new();
+        for _ in 0..200 {
+            versions = history.versions("gam", 42).unwrap();
+            if versions.len() == 3 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let infos = versions
+            .iter()
+            .map(|v| (v.version, v.entry["lessonInfo"].as_str().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(infos, [(1, "A1"), (2, "B2"), (3, "A1")]);
+        assert!(versions.iter().all(|v| v.element == -5 && v.day == day));
+        assert!(history.versions("gam", 7).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,67 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::URL_SAFE_PAD_INDIFFERENT, Engine};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+/// Refresh this long before `exp`, but at most a fifth of the token lifetime early
+const REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+/// Claims of the token handed out by `/WebUntis/api/token/new`. The signature is not
+/// checked, the token is only ever sent back to the server that issued it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub exp: u64,
+    #[serde(default)]
+    pub iat: Option<u64>,
+    #[serde(default)]
+    pub sub: Option<String>,
+    #[serde(default, deserialize_with = "string_or_number")]
+    pub tenant_id: Option<String>,
+    #[serde(default)]
+    pub person_id: Option<i64>,
+    #[serde(default)]
+    pub klasse_id: Option<i64>,
+    #[serde(default)]
+    pub roles: Option<String>,
+}
+
+impl Claims {
+    pub fn decode(token: &str) -> Option<Self> {
+        let payload = token.trim().split('.').nth(1)?;
+        let json = URL_SAFE_PAD_INDIFFERENT.decode(payload).ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+
+    pub fn expires_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.exp)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at() <= SystemTime::now()
+    }
+
+    /// Point in time at which the token should be replaced
+    pub fn refresh_at(&self) -> SystemTime {
+        let lifetime = self
+            .iat
+            .map(|iat| Duration::from_secs(self.exp.saturating_sub(iat)))
+            .unwrap_or(REFRESH_MARGIN * 5);
+        self.expires_at() - REFRESH_MARGIN.min(lifetime / 5)
+    }
+
+    /// Time left until `refresh_at`, zero if it already passed
+    pub fn refresh_in(&self) -> Duration {
+        self.refresh_at()
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+    }
+}
+
+fn string_or_number<'de, D: Deserializer<'de>>(d: D) -> Result<Option<String>, D::Error> {
+    Ok(match Option::<Value>::deserialize(d)? {
+        Some(Value::String(s)) => Some(s),
+        Some(Value::Number(n)) => Some(n.to_string()),
+        _ => None,
+    })
+}
@@ -0,0 +1,197 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use cookie::Key;
+use hmac::{Hmac, KeyInit, Mac};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use tracing::{error, info, warn};
+
+use crate::{
+    auth::{AuthError, Credentials},
+    breaker::backoff,
+    config::{LockoutConfig, SchoolConfig},
+    fetch::unix_now,
+};
+
+/// Longest pause between two login attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Persisted failure count of the full logins of one tenant
+#[derive(Default, Clone, Serialize, Deserialize)]
+struct LockoutState {
+    failures: u32,
+    /// Unix timestamp of the last rejected login
+    last_failure: Option<u64>,
+    last_error: Option<String>,
+    /// Fingerprint of the credentials that failed, a change unlocks the login
+    fingerprint: Option<String>,
+}
+
+pub enum Verdict {
+    Allowed,
+    /// Backing off until the given unix timestamp
+    Backoff(u64),
+    Locked,
+}
+
+#[derive(Serialize)]
+pub struct LoginStatus {
+    /// `ok`, `backoff` or `locked`
+    pub state: &'static str,
+    pub failures: u32,
+    pub max_failures: u32,
+    pub last_failure: Option<u64>,
+    pub last_error: Option<String>,
+    pub retry_at: Option<u64>,
+}
+
+/// Counts rejected logins across all fetch tasks of a tenant. After each credential
+/// failure the next attempt is delayed exponentially, after `max_failures` logins
+/// stay locked until an operator resets them or the credentials change.
+pub struct LoginGuard {
+    path: PathBuf,
+    config: LockoutConfig,
+    key: Key,
+    state: Mutex<LockoutState>,
+}
+
+impl LoginGuard {
+    pub fn new(state_dir: &Path, school: &SchoolConfig, key: Key) -> Self {
+        let path = state_dir.join(format!("{}.lockout", school.id));
+        let state = std::fs::read(&path)
+            .ok()
+            .and_then(|b| serde_json::from_slice(&b).ok())
+            .unwrap_or_default();
+        Self {
+            path,
+            config: school.lockout.clone(),
+            key,
+            state: Mutex::new(state),
+        }
+    }
+
+    /// Keyed hash over everything that decides whether a login can succeed
+    pub fn fingerprint(&self, school: &SchoolConfig, credentials: &Credentials) -> String {
+        let mut mac = Hmac::<Sha1>::new_from_slice(self.key.master()).expect("any key length");
+        mac.update(format!("{:?}", school.auth).as_bytes());
+        for part in [
+            Some(&credentials.username),
+            Some(&credentials.password),
+            credentials.totp.as_ref(),
+        ] {
+            mac.update(part.map_or("", |p| p.expose()).as_bytes());
+            mac.update(&[0]);
+        }
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    pub fn check(&self, fingerprint: &str) -> Verdict {
+        let mut state = self.state.lock().unwrap();
+        if state.failures > 0 && state.fingerprint.as_deref() != Some(fingerprint) {
+            info!(
+                "Credentials changed, clearing {} failed logins",
+                state.failures
+            );
+            *state = LockoutState::default();
+            self.save(&state);
+        }
+        self.verdict(&state)
+    }
+
+    fn verdict(&self, state: &LockoutState) -> Verdict {
+        if state.failures == 0 {
+            return Verdict::Allowed;
+        }
+        if state.failures >= self.config.max_failures {
+            return Verdict::Locked;
+        }
+        let retry_at = self.retry_at(state);
+        if retry_at > unix_now() {
+            Verdict::Backoff(retry_at)
+        } else {
+            Verdict::Allowed
+        }
+    }
+
+    fn retry_at(&self, state: &LockoutState) -> u64 {
+        let backoff = backoff(
+            Duration::from_secs(self.config.backoff),
+            state.failures,
+            MAX_BACKOFF,
+        );
+        state.last_failure.unwrap_or_default() + backoff.as_secs()
+    }
+
+    /// Records the outcome of a full login. Only rejected credentials count, network
+    /// errors cannot lock the account.
+    pub fn record(&self, fingerprint: &str, result: &Result<(String, String), AuthError>) {
+        let mut state = self.state.lock().unwrap();
+        match result {
+            Ok(_) if state.failures > 0 => {
+                *state = LockoutState::default();
+                self.save(&state);
+            }
+            Err(e) if e.is_credential_failure() => {
+                state.failures += 1;
+                state.last_failure = Some(unix_now());
+                state.last_error = Some(e.to_string());
+                state.fingerprint = Some(fingerprint.to_owned());
+                if state.failures >= self.config.max_failures {
+                    error!(
+                        "{} failed logins, no further attempts until the login is reset",
+                        state.failures
+                    );
+                } else {
+                    warn!(
+                        "Login rejected {} times, next attempt at {}",
+                        state.failures,
+                        self.retry_at(&state)
+                    );
+                }
+                self.save(&state);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        info!("Login reset after {} failures", state.failures);
+        *state = LockoutState::default();
+        self.save(&state);
+    }
+
+    pub fn status(&self) -> LoginStatus {
+        let state = self.state.lock().unwrap();
+        let (name, retry_at) = match self.verdict(&state) {
+            Verdict::Allowed => ("ok", None),
+            Verdict::Backoff(at) => ("backoff", Some(at)),
+            Verdict::Locked => ("locked", None),
+        };
+        LoginStatus {
+            state: name,
+            failures: state.failures,
+            max_failures: self.config.max_failures,
+            last_failure: state.last_failure,
+            last_error: state.last_error.clone(),
+            retry_at,
+        }
+    }
+
+    fn save(&self, state: &LockoutState) {
+        let res = serde_json::to_vec(state)
+            .map_err(std::io::Error::other)
+            .and_then(|json| std::fs::write(&self.path, json));
+        if let Err(e) = res {
+            warn!("Could not save {}: {e}", self.path.display());
+        }
+    }
+}
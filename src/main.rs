@@ -1,96 +1,142 @@
+mod auth;
+mod breaker;
+mod changes;
+mod config;
 mod definitions;
+mod digest;
+mod error;
+mod feed;
 mod fetch;
+mod history;
+mod jwt;
+mod lockout;
+mod metrics;
+mod mqtt;
+mod oauth;
+mod policy;
+mod sanity;
+mod schedule;
+mod secret;
+mod session;
+mod snapshot;
+mod state;
+mod supervisor;
+mod tenant;
+#[cfg(test)]
+mod testutil;
+mod totp;
+mod webhook;
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     fmt::Display,
-    fs::File,
     future::Future,
-    io::Read,
-    net::SocketAddr,
-    num::{NonZero, NonZeroU32},
     pin::Pin,
-    sync::{Arc, LazyLock},
+    sync::Arc,
 };
 
 use arcshift::ArcShift;
-use bytes::{Buf, Bytes};
-use chrono::Local;
-use dashmap::DashMap;
-use fetch::fetch;
-use governor::{DefaultDirectRateLimiter, Quota};
+use auth::{login, Credentials};
+use bytes::Bytes;
+use chrono::{Local, NaiveDate};
+use config::Config;
+use definitions::CalendarEntry;
+use error::Error;
+use fetch::{build_ttd, fetch, DayStatus};
+use history::History;
 use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
 use hyper::{
-    body::Incoming, header::HeaderValue, server::conn::http1, service::Service, Method, Request,
+    body::Incoming,
+    header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE},
+    server::conn::http1,
+    service::Service,
+    Method, Request, StatusCode,
 };
 use hyper_util::rt::TokioIo;
 use ics::{Event, ICalendar};
-use reqwest::{
-    cookie::{CookieStore, Jar},
-    Client, ClientBuilder, Url,
-};
+use jwt::Claims;
+use reqwest::Client;
+use sanity::HeldUpdate;
+use schedule::Schedule;
+use secret::{Secret, SecretRef};
 use serde::{Deserialize, Serialize};
+use supervisor::{TaskHandle, TaskState};
+use tenant::Tenant;
 use tokio::net::TcpListener;
-use tracing::{debug, error, info, info_span, warn, warn_span, Instrument, Level};
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-const GRADES: [isize; 24] = [
-    1908, 1905, 1902, 1899, 1896, 1893, 1890, 1887, 1884, 1881, 1878, 1875, 1872, 1869, 1866, 1863,
-    1860, 1857, 1854, 1851, 1848, 1845, 1842, 1839,
-];
-
-const SCHOOL_SPECIFIC_COOKIES: &str =
-    "schoolname=\"_Z3ltbmFzaXVtIGFtIG1hcmt0\"; Tenant-Id=\"5761300\";";
-
-pub static ALIAS: LazyLock<HashMap<String, String>> = LazyLock::new(|| {
-    let path = "./alias";
-    let mut buf = String::new();
-    File::create_new(path).ok();
-    File::open(path).unwrap().read_to_string(&mut buf).unwrap();
-
-    buf.split("\n")
-        .filter_map(|el| {
-            el.find(";")
-                .map(|i| el.split_at(i))
-                .map(|f| (f.0.to_owned(), f.1.strip_prefix(";").unwrap().to_owned()))
-        })
-        .collect::<HashMap<String, String>>()
-});
-
 #[derive(Clone)]
 struct Svc {
     rt: Arc<tokio::runtime::Runtime>,
     client: Client,
-    limiter: Arc<DefaultDirectRateLimiter>,
-    data: Arc<DashMap<isize, ArcShift<TimeTableData>>>,
+    tenants: Arc<Vec<Arc<Tenant>>>,
+    admin_token: Option<Secret>,
 }
 
 impl Svc {
-    pub fn new(rt: tokio::runtime::Runtime, limiter: DefaultDirectRateLimiter) -> Self {
+    pub fn new(
+        rt: tokio::runtime::Runtime,
+        tenants: Vec<Tenant>,
+        admin_token: Option<Secret>,
+    ) -> Self {
         Self {
             rt: Arc::new(rt),
-            limiter: Arc::new(limiter),
+            // shared by all schools, requests carry the timeout of their school
             client: Client::new(),
-            data: Arc::new(DashMap::new()),
+            tenants: Arc::new(tenants.into_iter().map(Arc::new).collect()),
+            admin_token,
         }
     }
 
-    pub fn get(&self, key: isize) -> ArcShift<TimeTableData> {
-        match self.data.get(&key) {
+    /// Whether the request carries the configured admin token
+    fn is_admin(&self, req: &Request<Incoming>) -> bool {
+        let Some(token) = &self.admin_token else {
+            return false;
+        };
+        req.headers()
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .is_some_and(|v| v == token.expose())
+    }
+
+    /// Splits a request path into the tenant and the path inside that tenant.
+    /// Paths without a `/s/<id>` prefix belong to the first configured school.
+    fn resolve(&self, path: &str) -> Option<(Arc<Tenant>, String)> {
+        let Some(rest) = path.strip_prefix("/s/") else {
+            return Some((self.tenants[0].clone(), path.to_owned()));
+        };
+        let (id, rest) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        let tenant = self.tenants.iter().find(|t| t.id() == id)?;
+        let rest = if rest.is_empty() { "/" } else { rest };
+        Some((tenant.clone(), rest.to_owned()))
+    }
+
+    pub fn get(&self, tenant: &Arc<Tenant>, key: isize) -> ArcShift<TimeTableData> {
+        match tenant.data.get(&key) {
             Some(d) => d.clone(),
             None => {
-                info!("Generiere neu {}", key);
-                let val = ArcShift::new(TimeTableData::default());
-                self.data.insert(key, val.clone());
+                info!("Generiere neu {} {}", tenant.id(), key);
+                // the snapshot is served until the first fetch replaces it
+                let data = match tenant.snapshots.load(key) {
+                    Some((entries, days)) => {
+                        let mut data = build_ttd(entries, tenant);
+                        data.days = days;
+                        data
+                    }
+                    None => TimeTableData::default(),
+                };
+                let val = ArcShift::new(data);
+                tenant.data.insert(key, val.clone());
                 {
                     let val = val.clone();
-                    let span = info_span!("ID", %key);
                     let client = self.client.clone();
-                    let limiter = self.limiter.clone();
+                    let tenant = tenant.clone();
                     tokio::task::Builder::new()
-                        .name(&format!("ID {key}"))
+                        .name(&format!("{} supervisor {key}", tenant.id()))
                         .spawn_on(
-                            async move { fetch_task(val, client, limiter,  key).instrument(span).await },
+                            supervisor::supervise(val, client, tenant, key),
                             self.rt.handle(),
                         )
                         .unwrap();
@@ -103,6 +149,11 @@ impl Svc {
 
 #[derive(Default)]
 struct TimeTableData {
+    /// Lessons by day, the calendar data below is built from them
+    entries: BTreeMap<NaiveDate, Vec<CalendarEntry>>,
+    days: BTreeMap<NaiveDate, DayStatus>,
+    /// Updates held back by the sanity gate, by tier
+    held: BTreeMap<usize, HeldUpdate>,
     blocks: HashMap<String, Vec<Event<'static>>>,
     tasks: HashMap<String, HashSet<Event<'static>>>,
     teachers: HashMap<String, HashSet<String>>,
@@ -121,27 +172,126 @@ impl Display for TimeTableData {
     }
 }
 
+/// Keeps the data of one element up to date. Returns why it gave up, the supervisor
+/// starts it again later.
 async fn fetch_task(
     mut arc: ArcShift<TimeTableData>,
     client: reqwest::Client,
-    limiter: Arc<DefaultDirectRateLimiter>,
+    tenant: Arc<Tenant>,
     e_id: isize,
-) {
+    handle: Arc<TaskHandle>,
+) -> String {
     info!("Task für {} gestartet", e_id);
-    let mut cookies = String::new();
-    'legs: loop {
-        if let Some((data, c)) = fetch(e_id, &client, &limiter, cookies.clone()).await {
-            cookies = c;
-            if data.blocks.is_empty() {
-                break 'legs;
+    let mut schedule = match tenant.schedules.get(&e_id) {
+        Some(schedule) => schedule.clone(),
+        None => Schedule::new(&tenant.school.tiers),
+    };
+    loop {
+        let (tier, at) = schedule.next();
+        handle.set(TaskState::Idle);
+        tokio::time::sleep_until(at).await;
+        handle.set(TaskState::Running);
+        let today = Local::now().date_naive();
+        let window = schedule::window(&tenant.school, today);
+
+        let in_window = |day: &NaiveDate| *day >= window.0 && *day <= window.1;
+        let mut entries = arc.get().entries.clone();
+        let mut days = arc.get().days.clone();
+        entries.retain(|day, _| in_window(day));
+        days.retain(|day, _| in_window(day));
+        let mut held = arc.get().held.clone();
+        let mut results = BTreeMap::new();
+        for (first, last) in schedule.slices(tier, window, today) {
+            results.extend(match fetch(e_id, &client, &tenant, first, last).await {
+                Ok(results) => results,
+                Err(e @ Error::CircuitOpen(_)) => {
+                    debug!("Abruf von {first} bis {last} ausgesetzt: {e}");
+                    fetch::fail_range(first, last, &e.to_string())
+                }
+                Err(e) => {
+                    error!(
+                        kind = e.kind(),
+                        "Abruf von {first} bis {last} fehlgeschlagen, probiere es später nochmal: {e}"
+                    );
+                    fetch::fail_range(first, last, &e.to_string())
+                }
+            });
+        }
+        let mut complete = results
+            .values()
+            .all(|r| matches!(r, fetch::DayResult::Fetched(_)));
+        match sanity::check(&tenant.school.sanity, &entries, &results) {
+            Some(suspicious) if !held.contains_key(&tier) => {
+                let update = HeldUpdate::new(tier, &results, suspicious);
+                warn!(
+                    "Aktualisierung von {} bis {} zurückgehalten: {}",
+                    update.first, update.last, update.reason
+                );
+                held.insert(tier, update);
+                // only failures are recorded, the fetched days wait for the next cycle
+                results.retain(|_, r| matches!(r, fetch::DayResult::Failed(_)));
+                complete = false;
+            }
+            suspicious => {
+                if let Some(update) = held.remove(&tier) {
+                    match suspicious {
+                        Some(_) => info!(
+                            "Zurückgehaltene Aktualisierung bestätigt: {}",
+                            update.reason
+                        ),
+                        None => {
+                            info!("Zurückgehaltene Aktualisierung verworfen, Daten wieder normal")
+                        }
+                    }
+                }
+            }
+        }
+        let changes = changes::diff(e_id, &entries, &results);
+        if !changes.is_empty() {
+            info!("{} Änderungen erkannt", changes.len());
+            let changes = tenant.changes.push(changes);
+            if e_id == tenant.default_key() {
+                tenant.webhooks.notify(&changes);
             }
-            arc.update(data)
+        }
+        if let Some(history) = &tenant.history {
+            history.record(
+                tenant.id(),
+                e_id,
+                results.iter().filter_map(|(day, r)| match r {
+                    fetch::DayResult::Fetched(lessons) => Some((*day, lessons.as_slice())),
+                    fetch::DayResult::Failed(_) => None,
+                }),
+            );
+        }
+        // days new to the window count as well, unlike in the change log
+        let changed = results.iter().any(|(day, r)| match r {
+            fetch::DayResult::Fetched(lessons) => entries.get(day) != Some(lessons),
+            fetch::DayResult::Failed(_) => false,
+        });
+        fetch::apply(&mut entries, &mut days, results);
+        if complete {
+            debug!("Stufe {tier} aktualisiert");
+            schedule.done(tier, tenant.period().factor(&tenant.school.polling));
         } else {
-            error!("Irgendwas ist beim holen der Daten schiefgelaufen, probiere es in 5 Minuten nochmal")
+            schedule.failed(tier);
         }
-        tokio::time::sleep(tokio::time::Duration::from_secs(300)).await;
+        tenant.schedules.insert(e_id, schedule.clone());
+
+        let mut data = build_ttd(entries, &tenant);
+        data.days = days;
+        data.held = held;
+        if data.blocks.is_empty() && complete && schedule.is_complete() {
+            // a holiday, a dead session shows up as SessionRejected and is dropped by fetch
+            return "no lessons in the whole window".to_owned();
+        }
+        if changed {
+            tenant
+                .snapshots
+                .save(e_id, data.entries.clone(), data.days.clone());
+        }
+        arc.update(data)
     }
-    warn!("Task für {} beendet, weil keine Daten bekommen", e_id);
 }
 
 #[tokio::main]
@@ -166,26 +316,113 @@ async fn main() {
     }
 
     dotenv::dotenv().ok();
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3022));
-    let listener = TcpListener::bind(addr).await.unwrap();
+    let config = match Config::load() {
+        Ok(c) => c,
+        Err(e) => {
+            error!("{e}");
+            std::process::exit(1);
+        }
+    };
+    let listener = TcpListener::bind(config.server.bind).await.unwrap();
 
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .unwrap();
 
-    let limiter = DefaultDirectRateLimiter::direct(Quota::per_second(NonZero::new(50).unwrap()));
-
-    let svc = Svc::new(rt, limiter);
+    let key = match state::load_key(&config.server.state_dir) {
+        Ok(k) => k,
+        Err(e) => {
+            error!("Could not load state key: {e}");
+            std::process::exit(1);
+        }
+    };
+    let state_dir = config.server.state_dir;
+    let admin_token = match config.server.admin_token.as_ref().map(SecretRef::resolve) {
+        Some(Err(e)) => {
+            error!("Could not read server.admin_token: {e}");
+            std::process::exit(1);
+        }
+        token => token.and_then(Result::ok),
+    };
+    let mailer = match config.server.smtp.as_ref().map(digest::Mailer::new) {
+        Some(Err(e)) => {
+            error!("Could not set up the SMTP relay: {e}");
+            std::process::exit(1);
+        }
+        mailer => mailer.and_then(Result::ok).map(Arc::new),
+    };
+    let history = match config.server.history.as_deref().map(History::open) {
+        Some(Err(e)) => {
+            error!("Could not open the lesson history: {e}");
+            std::process::exit(1);
+        }
+        history => history.and_then(Result::ok).map(Arc::new),
+    };
+    for school in &config.schools {
+        if let Err(e) = Credentials::resolve(&school.credentials) {
+            error!("Credentials of {} can not be read: {e}", school.id);
+        }
+    }
+    let tenants = match config
+        .schools
+        .into_iter()
+        .map(|school| Tenant::new(school, &state_dir, key.clone(), history.clone()))
+        .collect()
+    {
+        Ok(tenants) => tenants,
+        Err(e) => {
+            error!("{e}");
+            std::process::exit(1);
+        }
+    };
+    let svc = Svc::new(rt, tenants, admin_token);
+
+    for tenant in svc.tenants.iter() {
+        info!("Schule unter /s/{}/", tenant.id());
+        let refresher = tenant.clone();
+        tokio::task::Builder::new()
+            .name(&format!("{} session", tenant.id()))
+            .spawn_on(
+                async move { refresher.keep_session_fresh().await },
+                svc.rt.handle(),
+            )
+            .unwrap();
+        for el in &tenant.school.grades {
+            svc.get(tenant, -el);
+        }
+        if let Some(mailer) = &mailer {
+            for subscriber in &tenant.school.subscribers {
+                tokio::task::Builder::new()
+                    .name(&format!("{} digest {}", tenant.id(), subscriber.email))
+                    .spawn_on(
+                        digest::run(tenant.clone(), mailer.clone(), subscriber.clone()),
+                        svc.rt.handle(),
+                    )
+                    .unwrap();
+            }
+        }
+    }
 
-    // svc.get(-1908);
-    for el in GRADES {
-        svc.get(-el);
+    if let Some(mqtt) = config.server.mqtt {
+        let tenants = svc.tenants.clone();
+        tokio::task::Builder::new()
+            .name("mqtt")
+            .spawn_on(
+                async move {
+                    if let Err(e) = mqtt::run(mqtt, tenants).await {
+                        error!("Could not start the MQTT publisher: {e}");
+                    }
+                },
+                svc.rt.handle(),
+            )
+            .unwrap();
     }
 
     loop {
         let (stream, _) = listener.accept().await.unwrap();
-        svc.clone().get(-1908).reload();
+        let first = &svc.tenants[0];
+        svc.clone().get(first, first.default_key()).reload();
 
         let svc = svc.clone();
         let io = TokioIo::new(stream);
@@ -208,148 +445,11 @@ pub fn create_timestamp(stamp: &str) -> Option<String> {
     Some(time.format("%Y%m%dT%H%M%SZ").to_string())
 }
 
-pub async fn login(
-    username: Option<String>,
-    password: Option<String>,
-    cookies: Option<String>,
-) -> Option<(String, String)> {
-    if let Some(c) = cookies {
-        if let Some((token, cookies)) = try_refresh(c).await {
-            info!("Session could be recovered");
-            return Some((token, cookies));
-        }
-    }
-    info!("Creating new session and loggin in through oauth");
-    let mut untis_cookies = String::from(SCHOOL_SPECIFIC_COOKIES);
-
-    let url = "https://nessa.webuntis.com/WebUntis/oidc/login";
-    let cookie_jar = Arc::new(Jar::default());
-    let client = reqwest::Client::builder()
-        .cookie_store(true)
-        .cookie_provider(cookie_jar.clone())
-        .build()
-        .ok()?;
-    let res = client
-        .get(url)
-        .header("Cookie", &untis_cookies)
-        .send()
-        .await
-        .ok()?;
-    let redirect_url = res.url().clone();
-    let res = client.get(redirect_url).send().await.ok()?;
-    let login_url = res.url().clone();
-    let mut params = HashMap::new();
-    let username = match username {
-        Some(u) => u,
-        None => std::env::vars().find(|(k, _)| k == "USERNAME")?.1,
-    };
-    params.insert("_username", username);
-    let password = match password {
-        Some(p) => p,
-        None => std::env::vars().find(|(k, _)| k == "PASSWORD")?.1,
-    };
-    params.insert("_password", password);
-    let res = client.post(login_url).form(&params).send().await.ok()?;
-    let text = res.text().await.ok()?;
-    let redirect = text.split(";url=").nth(1)?.split("\">").next()?;
-    let res = client.get(redirect).send().await.ok()?;
-    let params = construct_oauth_params(
-        res.url().to_string(),
-        res.text().await.unwrap_or_default().to_string(),
-    );
-    let _res = client
-        .post("https://gamma-achim.de/iserv/oauth/v2/auth")
-        .form(&params)
-        .send()
-        .await
-        .ok()?;
-
-    let res = client
-        .get("https://nessa.webuntis.com/WebUntis/api/token/new")
-        .send()
-        .await
-        .ok()?;
-
-    let token = res.text().await.ok()?;
-    let url = Url::parse("https://nessa.webuntis.com/WebUntis").ok()?;
-    let needed_cookies = cookie_jar.cookies(&url)?;
-    untis_cookies.push_str(needed_cookies.to_str().ok()?);
-
-    Some((token, untis_cookies))
-}
-
-async fn try_refresh(cookies: String) -> Option<(String, String)> {
-    let client = reqwest::Client::builder().build().ok()?;
-    let res = client
-        .get("https://nessa.webuntis.com/WebUntis/api/token/new")
-        .header("Cookie", &cookies)
-        .send()
-        .await
-        .ok()?;
-    let token = res.text().await.ok()?;
-    if token.starts_with("<!doctype html>") {
-        warn!("Did not get a token");
-        return None;
-    }
-    Some((token, cookies))
-}
-
-fn construct_oauth_params(url: String, text: String) -> HashMap<&'static str, &'static str> {
-    let mut params = HashMap::new();
-    params.insert("accepted", "");
-    params.insert(
-        "iserv_oauth_server_authorize_form[client_id]",
-        "15_61zgj5ci0q4ows8swo80so0g4wkckgwsg40owkg4k8cc8cg04k",
-    );
-    params.insert("iserv_oauth_server_authorize_form[response_type]", "code");
-    // TODO: parse the URL, as it seems that it is prone to change
-    params.insert(
-        "iserv_oauth_server_authorize_form[redirect_uri]",
-        "https://oidc.webuntis.com/WebUntis/oidc/callback",
-    );
-    // TODO: decode URI Parts, as it might cause more problems in the future
-    let state = url
-        .split("state=")
-        .nth(1)
-        .unwrap()
-        .split("&")
-        .next()
-        .unwrap()
-        .replace("%3D", "=");
-    params.insert("iserv_oauth_server_authorize_form[state]", state.leak());
-    params.insert(
-        "iserv_oauth_server_authorize_form[scope]",
-        "openid email iserv:webuntis",
-    );
-    let nonce = url
-        .leak()
-        .split("nonce=")
-        .nth(1)
-        .unwrap()
-        .split("&")
-        .next()
-        .unwrap();
-    params.insert("iserv_oauth_server_authorize_form[nonce]", nonce);
-    let token = {
-        text.split("iserv_oauth_server_authorize_form__token")
-            .nth(1)
-            .unwrap()
-            .split("value=\"")
-            .nth(1)
-            .unwrap()
-            .split("\"")
-            .next()
-            .unwrap()
-            .to_owned()
-    };
-    params.insert("iserv_oauth_server_authorize_form[_token]", token.leak());
-    params
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Deserialize)]
 struct LoginData {
-    username: String,
-    password: String,
+    username: Secret,
+    password: Secret,
+    totp: Option<Secret>,
 }
 
 impl Service<Request<Incoming>> for Svc {
@@ -360,11 +460,15 @@ impl Service<Request<Incoming>> for Svc {
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn call(&self, req: Request<Incoming>) -> Self::Future {
-        debug!("{req:?}");
-        let res = match (req.method(), req.uri().path()) {
+        // only method and path, headers and bodies may carry tokens and passwords
+        debug!("{} {}", req.method(), req.uri().path());
+        let Some((tenant, path)) = self.resolve(req.uri().path()) else {
+            return Box::pin(async { Ok(not_found()) });
+        };
+        let res = match (req.method(), path.as_str()) {
             (&Method::GET, "/") => {
                 let options = self
-                    .get(-1908)
+                    .get(&tenant, tenant.default_key())
                     .blocks
                     .keys()
                     .fold(String::new(), |acc, el| format!("{acc}\n{el}"))
@@ -374,27 +478,98 @@ impl Service<Request<Incoming>> for Svc {
             }
             (&Method::GET, "/ics") => {
                 let mut calendar = ICalendar::new("2.0", "ics-rs");
-                add_to_calendar(&mut calendar, &self.get(-1908), "default");
+                add_to_calendar(
+                    &mut calendar,
+                    &self.get(&tenant, tenant.default_key()),
+                    "default",
+                );
                 req.uri()
                     .query()
                     .unwrap_or_default()
                     .split(',')
                     .for_each(|el| {
-                        add_to_calendar(&mut calendar, &self.get(-1908), el);
+                        add_to_calendar(
+                            &mut calendar,
+                            &self.get(&tenant, tenant.default_key()),
+                            el,
+                        );
                     });
                 let cal_string = calendar.to_string().replace(",", "\\,").replace(";", "\\;");
-                let res = hyper::http::response::Response::new(full(cal_string));
+                calendar_response(cal_string, tenant.breaker.open_since())
+            }
+            (&Method::GET, "/feed") => {
+                // starts the task of the default grade like /ics does
+                self.get(&tenant, tenant.default_key());
+                let query = req.uri().query().unwrap_or_default();
+                let courses = query
+                    .split(',')
+                    .filter(|c| !c.is_empty())
+                    .collect::<Vec<_>>();
+                // behind a reverse proxy the original scheme is only known from its header
+                let header = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+                let url = format!(
+                    "{}://{}{}",
+                    header("x-forwarded-proto").unwrap_or("http"),
+                    header("host").unwrap_or("localhost"),
+                    req.uri()
+                );
+                let res =
+                    hyper::http::response::Response::new(full(feed::atom(&tenant, &courses, &url)));
                 let (mut parts, body) = res.into_parts();
-                parts
-                    .headers
-                    .insert("conent-type", HeaderValue::from_static("text/calendar"));
+                parts.headers.insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("application/atom+xml"),
+                );
+                hyper::http::response::Response::from_parts(parts, body)
+            }
+            (&Method::GET, "/status") => json_response(&tenant.status()),
+            (&Method::GET, "/changes") => {
+                let since = req
+                    .uri()
+                    .query()
+                    .unwrap_or_default()
+                    .split('&')
+                    .find_map(|p| p.strip_prefix("since="))
+                    .map_or(Ok(0), str::parse::<u64>);
+                match since {
+                    Ok(since) => json_response(&tenant.changes.since(since)),
+                    Err(_) => {
+                        let mut res = hyper::http::response::Response::new(empty());
+                        *res.status_mut() = StatusCode::BAD_REQUEST;
+                        res
+                    }
+                }
+            }
+            (&Method::GET, "/claims") => {
+                // the claims identify the account the service logs in with
+                if !self.is_admin(&req) {
+                    return Box::pin(async { Ok(unauthorized()) });
+                }
+                hyper::http::response::Response::new(full(
+                    serde_json::to_string(&tenant.claims()).unwrap_or_default(),
+                ))
+            }
+            (&Method::GET, "/admin/tasks") => {
+                if !self.is_admin(&req) {
+                    return Box::pin(async { Ok(unauthorized()) });
+                }
+                json_response(&tenant.tasks.status())
+            }
+            (&Method::GET, "/metrics") => {
+                let res =
+                    hyper::http::response::Response::new(full(metrics::render(&self.tenants)));
+                let (mut parts, body) = res.into_parts();
+                parts.headers.insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("text/plain; version=0.0.4"),
+                );
                 hyper::http::response::Response::from_parts(parts, body)
             }
             (&Method::GET, "/t") => {
                 let mut calendar = ICalendar::new("2.0", "ics-rs");
                 let teacher = req.uri().query().unwrap_or_default().to_string();
-                for g in GRADES {
-                    let ttd = self.get(-g);
+                for g in &tenant.school.grades {
+                    let ttd = self.get(&tenant, -g);
                     let class = ttd.teachers.get(&teacher);
                     if let Some(c) = class {
                         for c in c {
@@ -403,18 +578,11 @@ impl Service<Request<Incoming>> for Svc {
                     }
                 }
                 let cal_string = calendar.to_string().replace(",", "\\,").replace(";", "\\;");
-                let res = hyper::http::response::Response::new(full(cal_string));
-                let (mut parts, body) = res.into_parts();
-                parts
-                    .headers
-                    .insert("conent-type", HeaderValue::from_static("text/calendar"));
-                hyper::http::response::Response::from_parts(parts, body)
+                calendar_response(cal_string, tenant.breaker.open_since())
             }
             (&Method::GET, _) => {
-                if req.uri().path().starts_with("/ics/") {
-                    let id = req
-                        .uri()
-                        .path()
+                if path.starts_with("/ics/") {
+                    let id = path
                         .trim_start_matches("/ics/")
                         .parse::<isize>()
                         .unwrap_or_default();
@@ -424,39 +592,88 @@ impl Service<Request<Incoming>> for Svc {
                     // If by person, just fetch one full week to find the courses they have and then use the grade data (cache person id relations)
                     let mut calendar = ICalendar::new("2.0", "ics-rs");
                     // let mut q = req.uri().query().unwrap_or_default().split(',');
-                    self.get(id)
+                    self.get(&tenant, id)
                         .blocks
                         .iter()
                         .filter(|(name, list)| {
                             name.contains("default")
                                 || !list.iter().all(|el| el.to_string().contains("➕"))
                         })
-                        .for_each(|(k, _)| add_to_calendar(&mut calendar, &self.get(id), k));
+                        .for_each(|(k, _)| {
+                            add_to_calendar(&mut calendar, &self.get(&tenant, id), k)
+                        });
                     // add_to_calendar(&mut calendar, &self.get(id), "default");
                     let cal_string = calendar.to_string().replace(",", "\\,").replace(";", "\\;");
-                    let res = hyper::http::response::Response::new(full(cal_string));
-                    let (mut parts, body) = res.into_parts();
-                    parts
-                        .headers
-                        .insert("conent-type", HeaderValue::from_static("text/calendar"));
-                    hyper::http::response::Response::from_parts(parts, body)
+                    calendar_response(cal_string, tenant.breaker.open_since())
+                } else if let Some(id) = path.strip_prefix("/history/") {
+                    let (Some(history), Ok(id)) = (&tenant.history, id.parse::<i64>()) else {
+                        return Box::pin(async { Ok(not_found()) });
+                    };
+                    match history.versions(tenant.id(), id) {
+                        Ok(versions) => json_response(&versions),
+                        Err(e) => {
+                            error!("Could not read the lesson history: {e}");
+                            let mut res = hyper::http::response::Response::new(empty());
+                            *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                            res
+                        }
+                    }
                 } else {
                     hyper::http::response::Response::new(empty())
                 }
             }
+            (&Method::POST, "/admin/reset-login") => {
+                if !self.is_admin(&req) {
+                    return Box::pin(async { Ok(unauthorized()) });
+                }
+                tenant.sessions.reset_login();
+                json_response(&tenant.sessions.login_status())
+            }
+            (&Method::POST, p) if p.starts_with("/admin/tasks/") => {
+                if !self.is_admin(&req) {
+                    return Box::pin(async { Ok(unauthorized()) });
+                }
+                // /admin/tasks/<element>/stop or /admin/tasks/<element>/start
+                let mut parts = p.trim_start_matches("/admin/tasks/").split('/');
+                let element = parts.next().and_then(|e| e.parse::<isize>().ok());
+                let found = match (element, parts.next()) {
+                    (Some(e), Some("stop")) => tenant.tasks.stop(e),
+                    (Some(e), Some("start")) => tenant.tasks.start(e),
+                    _ => false,
+                };
+                if found {
+                    json_response(&tenant.tasks.status())
+                } else {
+                    not_found()
+                }
+            }
             (&Method::POST, "/id") => {
                 // TODO: Do login and get the jwt token to fetch the person and class id
                 // println!("{:?}", req.body().collect());
                 return Box::pin(async move {
-                    let collected = req.into_body().collect().await.unwrap();
-                    let d =
-                        serde_json::from_slice::<LoginData>(collected.aggregate().chunk()).unwrap();
-                    let Some((token, _)) = login(Some(d.username), Some(d.password), None).await
-                    else {
-                        panic!("")
+                    let body = match req.into_body().collect().await {
+                        Ok(collected) => collected.to_bytes(),
+                        Err(e) => return Ok(bad_request(&e.to_string())),
+                    };
+                    let d = match serde_json::from_slice::<LoginData>(&body) {
+                        Ok(d) => d,
+                        Err(e) => return Ok(bad_request(&e.to_string())),
                     };
+                    let credentials = Credentials {
+                        username: d.username,
+                        password: d.password,
+                        totp: d.totp,
+                    };
+                    let token = match login(&tenant.school, tenant.auth.as_ref(), &credentials)
+                        .await
+                        .map_err(Error::from)
+                    {
+                        Ok((token, _)) => token,
+                        Err(e) => return Ok(error_response(StatusCode::UNAUTHORIZED, &e)),
+                    };
+                    let claims = Claims::decode(&token);
                     Ok(hyper::http::response::Response::new(full(
-                        token.leak().split(".").nth(1).unwrap(),
+                        serde_json::to_string(&claims).unwrap_or_default(),
                     )))
                 });
             }
@@ -467,6 +684,76 @@ impl Service<Request<Incoming>> for Svc {
     }
 }
 
+/// Calendar response, marked stale while the circuit breaker keeps upstream away
+fn calendar_response(
+    cal_string: String,
+    stale_since: Option<u64>,
+) -> hyper::http::response::Response<BoxBody<Bytes, hyper::Error>> {
+    let res = hyper::http::response::Response::new(full(cal_string));
+    let (mut parts, body) = res.into_parts();
+    parts
+        .headers
+        .insert(CONTENT_TYPE, HeaderValue::from_static("text/calendar"));
+    if let Some(since) = stale_since {
+        parts.headers.insert(
+            "warning",
+            HeaderValue::from_static("110 - \"Response is Stale\""),
+        );
+        parts
+            .headers
+            .insert("x-stale-since", HeaderValue::from(since));
+    }
+    hyper::http::response::Response::from_parts(parts, body)
+}
+
+fn json_response<T: Serialize>(
+    value: &T,
+) -> hyper::http::response::Response<BoxBody<Bytes, hyper::Error>> {
+    let res = hyper::http::response::Response::new(full(
+        serde_json::to_string(value).unwrap_or_default(),
+    ));
+    let (mut parts, body) = res.into_parts();
+    parts
+        .headers
+        .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    hyper::http::response::Response::from_parts(parts, body)
+}
+
+/// JSON body with the kind and message of `error`
+fn error_response(
+    status: StatusCode,
+    error: &Error,
+) -> hyper::http::response::Response<BoxBody<Bytes, hyper::Error>> {
+    let mut res = json_response(&serde_json::json!({
+        "kind": error.kind(),
+        "error": error.to_string(),
+    }));
+    *res.status_mut() = status;
+    res
+}
+
+/// JSON body like `error_response` for a request that can not be processed
+fn bad_request(message: &str) -> hyper::http::response::Response<BoxBody<Bytes, hyper::Error>> {
+    let mut res = json_response(&serde_json::json!({
+        "kind": "bad_request",
+        "error": message,
+    }));
+    *res.status_mut() = StatusCode::BAD_REQUEST;
+    res
+}
+
+fn unauthorized() -> hyper::http::response::Response<BoxBody<Bytes, hyper::Error>> {
+    let mut res = hyper::http::response::Response::new(empty());
+    *res.status_mut() = StatusCode::UNAUTHORIZED;
+    res
+}
+
+fn not_found() -> hyper::http::response::Response<BoxBody<Bytes, hyper::Error>> {
+    let mut res = hyper::http::response::Response::new(empty());
+    *res.status_mut() = StatusCode::NOT_FOUND;
+    res
+}
+
 fn empty() -> BoxBody<Bytes, hyper::Error> {
     Empty::<Bytes>::new()
         .map_err(|never| match never {})
@@ -0,0 +1,86 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use serde::Serialize;
+
+use crate::{error::Error, fetch::unix_now, tenant::Tenant};
+
+#[derive(Clone, Serialize)]
+pub struct ErrorRecord {
+    pub kind: &'static str,
+    pub message: String,
+    /// Unix timestamp
+    pub at: u64,
+}
+
+#[derive(Serialize)]
+pub struct ErrorStatus {
+    /// Errors since the start, by kind
+    pub counts: BTreeMap<&'static str, u64>,
+    pub last: Option<ErrorRecord>,
+}
+
+/// Upstream requests and errors of one tenant
+#[derive(Default)]
+pub struct Metrics {
+    requests: AtomicU64,
+    errors: Mutex<BTreeMap<&'static str, u64>>,
+    last_error: Mutex<Option<ErrorRecord>>,
+}
+
+impl Metrics {
+    pub fn request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn error(&self, error: &Error) {
+        *self.errors.lock().unwrap().entry(error.kind()).or_default() += 1;
+        *self.last_error.lock().unwrap() = Some(ErrorRecord {
+            kind: error.kind(),
+            message: error.to_string(),
+            at: unix_now(),
+        });
+    }
+
+    pub fn status(&self) -> ErrorStatus {
+        ErrorStatus {
+            counts: self.errors.lock().unwrap().clone(),
+            last: self.last_error.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// Counters of all tenants in the Prometheus text format
+pub fn render(tenants: &[Arc<Tenant>]) -> String {
+    let mut out = String::new();
+    out.push_str("# HELP untis_requests_total Requests sent to WebUntis\n");
+    out.push_str("# TYPE untis_requests_total counter\n");
+    for tenant in tenants {
+        let requests = tenant.metrics.requests.load(Ordering::Relaxed);
+        writeln!(
+            out,
+            "untis_requests_total{{school=\"{}\"}} {requests}",
+            tenant.id()
+        )
+        .unwrap();
+    }
+    out.push_str("# HELP untis_errors_total Failed logins and requests by kind\n");
+    out.push_str("# TYPE untis_errors_total counter\n");
+    for tenant in tenants {
+        for (kind, count) in tenant.metrics.errors.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "untis_errors_total{{school=\"{}\",kind=\"{kind}\"}} {count}",
+                tenant.id()
+            )
+            .unwrap();
+        }
+    }
+    out
+}
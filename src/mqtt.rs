@@ -0,0 +1,604 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use chrono::{Days, Local, NaiveDate, NaiveDateTime};
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::json;
+use tokio::sync::{broadcast::error::RecvError, watch};
+use tracing::{info, warn};
+
+use crate::{
+    config::{in_courses, MqttConfig, ProfileConfig},
+    definitions::Status,
+    fetch::{is_additional, is_substitution},
+    tenant::Tenant,
+};
+
+/// How often the time dependent topics like the next lesson are recomputed
+const REFRESH: Duration = Duration::from_secs(60);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Connects to the broker and keeps the topics of every profile up to date, runs
+/// forever. Retained topics are only republished when their value changes or after
+/// a reconnect.
+pub async fn run(config: MqttConfig, tenants: Arc<Vec<Arc<Tenant>>>) -> Result<(), String> {
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    let status = format!("{}/status", config.prefix);
+    options.set_last_will(LastWill::new(&status, "offline", QoS::AtLeastOnce, true));
+    if let (Some(username), Some(password)) = (&config.username, &config.password) {
+        let username = username.resolve().map_err(|e| e.to_string())?;
+        let password = password.resolve().map_err(|e| e.to_string())?;
+        options.set_credentials(username.expose(), password.expose());
+    }
+    let (client, mut eventloop) = AsyncClient::new(options, 256);
+
+    // counts the connections, each one makes the publishers start over
+    let connected = watch::Sender::new(0u32);
+    for tenant in tenants.iter().filter(|t| !t.school.profiles.is_empty()) {
+        tokio::spawn(publish_tenant(
+            client.clone(),
+            config.clone(),
+            tenant.clone(),
+            connected.subscribe(),
+        ));
+    }
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker {}:{}", config.host, config.port);
+                // only this loop drains the request queue, awaiting a full one here
+                // would block it for good
+                if client
+                    .try_publish(&status, QoS::AtLeastOnce, true, "online")
+                    .is_err()
+                {
+                    warn!("Request queue full, publishing the MQTT status later");
+                    let (client, status) = (client.clone(), status.clone());
+                    tokio::spawn(async move {
+                        client
+                            .publish(status, QoS::AtLeastOnce, true, "online")
+                            .await
+                            .ok();
+                    });
+                }
+                connected.send_modify(|n| *n += 1);
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("MQTT connection failed: {e}");
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+async fn publish_tenant(
+    client: AsyncClient,
+    config: MqttConfig,
+    tenant: Arc<Tenant>,
+    mut connected: watch::Receiver<u32>,
+) {
+    let mut changes = tenant.changes.subscribe();
+    let mut published = HashMap::new();
+    let mut tick = tokio::time::interval(REFRESH);
+    if connected.wait_for(|n| *n > 0).await.is_err() {
+        return;
+    }
+    announce(&client, &config, &tenant).await;
+    loop {
+        tokio::select! {
+            res = connected.changed() => {
+                if res.is_err() {
+                    return;
+                }
+                // the broker may have lost its retained messages
+                published.clear();
+                announce(&client, &config, &tenant).await;
+            }
+            _ = tick.tick() => {}
+            res = changes.recv() => match res {
+                Ok(batch) => {
+                    for profile in &tenant.school.profiles {
+                        let matching = batch
+                            .iter()
+                            .filter(|c| c.element == tenant.default_key())
+                            .filter(|c| in_courses(&profile.courses, c.subject.as_deref()))
+                            .collect::<Vec<_>>();
+                        if !matching.is_empty() {
+                            let topic = format!("{}/changes", base(&config, &tenant, profile));
+                            publish(&client, topic, false, json!(matching).to_string()).await;
+                        }
+                    }
+                }
+                // the skipped batches are lost but the states below still catch up
+                Err(RecvError::Lagged(n)) => warn!("{n} batches of changes not published"),
+                Err(RecvError::Closed) => return,
+            },
+        }
+        let now = Local::now().naive_local();
+        for profile in &tenant.school.profiles {
+            for (topic, payload) in states(&config, &tenant, profile, now) {
+                if published.get(&topic) != Some(&payload) {
+                    publish(&client, topic.clone(), true, payload.clone()).await;
+                    published.insert(topic, payload);
+                }
+            }
+        }
+    }
+}
+
+async fn announce(client: &AsyncClient, config: &MqttConfig, tenant: &Tenant) {
+    for profile in &tenant.school.profiles {
+        for (topic, payload) in discovery(config, tenant, profile) {
+            publish(client, topic, true, payload).await;
+        }
+    }
+}
+
+async fn publish(client: &AsyncClient, topic: String, retain: bool, payload: String) {
+    if let Err(e) = client
+        .publish(&topic, QoS::AtLeastOnce, retain, payload)
+        .await
+    {
+        warn!("Could not publish {topic}: {e}");
+    }
+}
+
+fn base(config: &MqttConfig, tenant: &Tenant, profile: &ProfileConfig) -> String {
+    format!("{}/{}/{}", config.prefix, tenant.id(), profile.name)
+}
+
+/// Course names may contain characters that are not allowed in ids and topic levels
+fn slug(course: &str) -> String {
+    course
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+/// Retained topics of `profile` at `now`, derived from the lessons of the default grade
+fn states(
+    config: &MqttConfig,
+    tenant: &Tenant,
+    profile: &ProfileConfig,
+    now: NaiveDateTime,
+) -> Vec<(String, String)> {
+    let base = base(config, tenant, profile);
+    let Some(data) = tenant.data.get(&tenant.default_key()) else {
+        return Vec::new();
+    };
+    let data = data.shared_get();
+    let mut lessons = data
+        .entries
+        .values()
+        .flatten()
+        .filter(|e| {
+            let subject = e.subject.as_ref().map(|s| s.display_name.as_str());
+            in_courses(&profile.courses, subject)
+        })
+        .filter_map(|e| Some((parse(&e.start_date_time)?, e)))
+        .collect::<Vec<_>>();
+    lessons.sort_by_key(|(start, _)| *start);
+    let upcoming = || lessons.iter().filter(|(start, _)| *start > now);
+
+    let today = now.date();
+    let tomorrow = today + Days::new(1);
+    let on = |day: NaiveDate| lessons.iter().filter(move |(start, _)| start.date() == day);
+    let first_lesson = |day| {
+        on(day)
+            .find(|(_, e)| e.status != Status::Cancelled)
+            .map_or("None".to_owned(), |(start, _)| timestamp(*start))
+    };
+    let cancelled = |day| {
+        on(day)
+            .filter(|(_, e)| e.status == Status::Cancelled)
+            .count()
+            .to_string()
+    };
+    let next_lesson = upcoming()
+        .find(|(_, e)| e.status != Status::Cancelled)
+        .map_or(json!(null), |(start, e)| {
+            json!({
+                "subject": e.subject.as_ref().map(|s| &s.display_name),
+                "start": timestamp(*start),
+                "end": parse(&e.end_date_time).map(timestamp),
+                "rooms": e.rooms.iter().filter(|r| r.status != Status::Removed).map(|r| &r.display_name).collect::<Vec<_>>(),
+                "substitution": is_substitution(e),
+                "additional": is_additional(e),
+            })
+        });
+
+    let mut states = vec![
+        (format!("{base}/next_lesson"), next_lesson.to_string()),
+        (format!("{base}/first_lesson/today"), first_lesson(today)),
+        (
+            format!("{base}/first_lesson/tomorrow"),
+            first_lesson(tomorrow),
+        ),
+        (format!("{base}/cancelled/today"), cancelled(today)),
+        (format!("{base}/cancelled/tomorrow"), cancelled(tomorrow)),
+    ];
+    for course in &profile.courses {
+        let next = upcoming().find(|(_, e)| {
+            e.subject
+                .as_ref()
+                .is_some_and(|s| &s.display_name == course)
+        });
+        let cancelled = next.is_some_and(|(_, e)| e.status == Status::Cancelled);
+        states.push((
+            format!("{base}/course/{}/cancelled", slug(course)),
+            if cancelled { "ON" } else { "OFF" }.to_owned(),
+        ));
+    }
+    states
+}
+
+/// Home Assistant discovery payloads for the topics of `profile`
+fn discovery(
+    config: &MqttConfig,
+    tenant: &Tenant,
+    profile: &ProfileConfig,
+) -> Vec<(String, String)> {
+    if config.discovery_prefix.is_empty() {
+        return Vec::new();
+    }
+    let base = base(config, tenant, profile);
+    let node = format!("untis_{}_{}", slug(tenant.id()), slug(&profile.name));
+    let device = json!({
+        "identifiers": [node],
+        "name": format!("Stundenplan {}", profile.name),
+        "manufacturer": "UntisCalendarStreamer",
+    });
+    let entity = |component: &str, key: &str, name: &str, mut payload: serde_json::Value| {
+        payload["name"] = json!(name);
+        payload["unique_id"] = json!(format!("{node}_{key}"));
+        payload["device"] = device.clone();
+        payload["availability_topic"] = json!(format!("{}/status", config.prefix));
+        (
+            format!(
+                "{}/{component}/{node}/{key}/config",
+                config.discovery_prefix
+            ),
+            payload.to_string(),
+        )
+    };
+
+    let mut entities = vec![
+        entity(
+            "sensor",
+            "next_lesson",
+            "Nächste Stunde",
+            json!({
+                "state_topic": format!("{base}/next_lesson"),
+                "value_template": "{{ value_json.subject if value_json else none }}",
+                "json_attributes_topic": format!("{base}/next_lesson"),
+            }),
+        ),
+        entity(
+            "sensor",
+            "first_lesson_today",
+            "Erste Stunde heute",
+            json!({
+                "state_topic": format!("{base}/first_lesson/today"),
+                "device_class": "timestamp",
+            }),
+        ),
+        entity(
+            "sensor",
+            "first_lesson_tomorrow",
+            "Erste Stunde morgen",
+            json!({
+                "state_topic": format!("{base}/first_lesson/tomorrow"),
+                "device_class": "timestamp",
+            }),
+        ),
+        entity(
+            "sensor",
+            "cancelled_today",
+            "Ausfälle heute",
+            json!({ "state_topic": format!("{base}/cancelled/today") }),
+        ),
+        entity(
+            "sensor",
+            "cancelled_tomorrow",
+            "Ausfälle morgen",
+            json!({ "state_topic": format!("{base}/cancelled/tomorrow") }),
+        ),
+    ];
+    for course in &profile.courses {
+        let course_slug = slug(course);
+        entities.push(entity(
+            "binary_sensor",
+            &format!("{course_slug}_cancelled"),
+            &format!("{course} fällt aus"),
+            json!({
+                "state_topic": format!("{base}/course/{course_slug}/cancelled"),
+                "payload_on": "ON",
+                "payload_off": "OFF",
+            }),
+        ));
+    }
+    entities
+}
+
+/// Local time of a WebUntis timestamp like `2025-03-03T08:00`
+fn parse(stamp: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_and_remainder(stamp, "%Y-%m-%dT%H:%M")
+        .ok()
+        .map(|(time, _)| time)
+}
+
+fn timestamp(local: NaiveDateTime) -> String {
+    local
+        .and_local_timezone(Local)
+        .earliest()
+        .map_or("None".to_owned(), |t| t.to_rfc3339())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        sync::Mutex,
+        time::{Duration, Instant},
+    };
+
+    use arcshift::ArcShift;
+    use bytes::BytesMut;
+    use chrono::NaiveTime;
+    use rumqttc::mqttbytes::{
+        self,
+        v4::{self, ConnAck, ConnectReturnCode, PingResp, PubAck},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::{
+        changes::{Change, ChangeKind},
+        definitions::{CalendarEntry, Subject},
+        testutil, TimeTableData,
+    };
+
+    use super::*;
+
+    /// What a client sent to the broker, in order
+    #[derive(Default)]
+    struct Received {
+        connects: Vec<v4::Connect>,
+        publishes: Vec<v4::Publish>,
+    }
+
+    impl Received {
+        /// Last payload published to `topic`, with its retain flag
+        fn last(&self, topic: &str) -> Option<(String, bool)> {
+            self.publishes
+                .iter()
+                .rev()
+                .find(|p| p.topic == topic)
+                .map(|p| (String::from_utf8_lossy(&p.payload).into_owned(), p.retain))
+        }
+    }
+
+    /// Minimal broker on a free local port that acknowledges everything and records
+    /// what it receives. The first connection is dropped after `drop_after` publishes.
+    async fn broker(drop_after: Option<usize>) -> (u16, Arc<Mutex<Received>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let received = Arc::new(Mutex::new(Received::default()));
+        let shared = received.clone();
+        tokio::spawn(async move {
+            let mut drop_after = drop_after;
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let limit = drop_after.take();
+                let mut publishes = 0;
+                let mut buf = BytesMut::new();
+                loop {
+                    let mut out = BytesMut::new();
+                    match v4::read(&mut buf, 1 << 20) {
+                        Ok(v4::Packet::Connect(connect)) => {
+                            shared.lock().unwrap().connects.push(connect);
+                            ConnAck::new(ConnectReturnCode::Success, false)
+                                .write(&mut out)
+                                .unwrap();
+                        }
+                        Ok(v4::Packet::Publish(publish)) => {
+                            if publish.qos == mqttbytes::QoS::AtLeastOnce {
+                                PubAck::new(publish.pkid).write(&mut out).unwrap();
+                            }
+                            shared.lock().unwrap().publishes.push(publish);
+                            publishes += 1;
+                            if Some(publishes) == limit {
+                                break;
+                            }
+                        }
+                        Ok(v4::Packet::PingReq) => {
+                            PingResp.write(&mut out).unwrap();
+                        }
+                        Ok(v4::Packet::Disconnect) => break,
+                        Ok(_) => {}
+                        Err(mqttbytes::Error::InsufficientBytes(_)) => {
+                            if stream.read_buf(&mut buf).await.unwrap_or(0) == 0 {
+                                break;
+                            }
+                        }
+                        Err(e) => panic!("broker could not read packet: {e}"),
+                    }
+                    if stream.write_all(&out).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        (port, received)
+    }
+
+    /// Waits until `done` holds for what the broker received
+    async fn wait_for(received: &Mutex<Received>, done: impl Fn(&Received) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(20);
+        while !done(&received.lock().unwrap()) {
+            assert!(Instant::now() < deadline, "broker did not receive in time");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    fn config(port: u16) -> MqttConfig {
+        toml::from_str(&format!(
+            r#"
+            host = "127.0.0.1"
+            port = {port}
+            "#
+        ))
+        .unwrap()
+    }
+
+    fn lesson(id: i64, start: NaiveDateTime, cancelled: bool) -> CalendarEntry {
+        let end = start + Duration::from_secs(45 * 60);
+        CalendarEntry {
+            id,
+            start_date_time: start.format("%Y-%m-%dT%H:%M").to_string(),
+            end_date_time: end.format("%Y-%m-%dT%H:%M").to_string(),
+            subject: Some(Subject {
+                display_name: "MA1".to_owned(),
+                ..Default::default()
+            }),
+            status: if cancelled {
+                Status::Cancelled
+            } else {
+                Status::TakingPlace
+            },
+            ..Default::default()
+        }
+    }
+
+    /// Timetable with two lessons of MA1 tomorrow, the second one cancelled
+    fn data(first_cancelled: bool) -> TimeTableData {
+        let tomorrow = Local::now().date_naive() + Days::new(1);
+        let at = |h| tomorrow.and_time(NaiveTime::from_hms_opt(h, 0, 0).unwrap());
+        TimeTableData {
+            entries: BTreeMap::from([(
+                tomorrow,
+                vec![lesson(1, at(8), first_cancelled), lesson(2, at(10), true)],
+            )]),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn publishes_status_discovery_states_and_changes() {
+        let (port, received) = broker(None).await;
+        let config = config(port);
+        let (tenant, _dir) = testutil::tenant(
+            "http://127.0.0.1:1",
+            r#"
+            [[profile]]
+            name = "anna"
+            courses = ["MA1"]
+            "#,
+        );
+        let key = tenant.default_key();
+        tenant.data.insert(key, ArcShift::new(data(false)));
+        let tenant = Arc::new(tenant);
+        tokio::spawn(run(config.clone(), Arc::new(vec![tenant.clone()])));
+
+        let base = "untis/test/anna";
+        let states = [
+            format!("{base}/next_lesson"),
+            format!("{base}/first_lesson/today"),
+            format!("{base}/first_lesson/tomorrow"),
+            format!("{base}/cancelled/today"),
+            format!("{base}/cancelled/tomorrow"),
+            format!("{base}/course/MA1/cancelled"),
+        ];
+        wait_for(&received, |r| states.iter().all(|t| r.last(t).is_some())).await;
+        {
+            let received = received.lock().unwrap();
+            let will = received.connects[0].last_will.as_ref().unwrap();
+            assert_eq!(will.topic, "untis/status");
+            assert_eq!(&will.message[..], b"offline");
+            assert!(will.retain);
+            assert_eq!(
+                received.last("untis/status"),
+                Some(("online".to_owned(), true))
+            );
+            let config = received
+                .last("homeassistant/binary_sensor/untis_test_anna/MA1_cancelled/config")
+                .unwrap();
+            assert!(config
+                .0
+                .contains(r#""state_topic":"untis/test/anna/course/MA1/cancelled""#));
+            assert!(received
+                .last("homeassistant/sensor/untis_test_anna/next_lesson/config")
+                .is_some());
+            assert!(states.iter().all(|t| received.last(t).unwrap().1));
+            let next: serde_json::Value =
+                serde_json::from_str(&received.last(&states[0]).unwrap().0).unwrap();
+            assert_eq!(next["subject"], "MA1");
+            assert_eq!(received.last(&states[3]).unwrap().0, "0");
+            assert_eq!(received.last(&states[4]).unwrap().0, "1");
+            assert_eq!(received.last(&states[5]).unwrap().0, "OFF");
+        }
+
+        // the first lesson is cancelled too
+        tenant.data.get_mut(&key).unwrap().update(data(true));
+        let tomorrow = Local::now().date_naive() + Days::new(1);
+        tenant.changes.push(vec![Change {
+            at: 0,
+            element: key,
+            day: tomorrow,
+            lesson: 1,
+            subject: Some("MA1".to_owned()),
+            start: "08:00".to_owned(),
+            end: "08:45".to_owned(),
+            kind: ChangeKind::Cancelled,
+        }]);
+        wait_for(&received, |r| r.last(&format!("{base}/changes")).is_some()).await;
+        wait_for(&received, |r| {
+            r.last(&states[5]) == Some(("ON".to_owned(), true))
+        })
+        .await;
+        let received = received.lock().unwrap();
+        let (changes, retain) = received.last(&format!("{base}/changes")).unwrap();
+        assert!(!retain);
+        let changes: serde_json::Value = serde_json::from_str(&changes).unwrap();
+        assert_eq!(changes[0]["lesson"], 1);
+        assert_eq!(received.last(&states[4]).unwrap().0, "2");
+    }
+
+    #[tokio::test]
+    async fn reconnects_while_the_request_queue_is_full() {
+        // enough topics to fill the request queue while the client is disconnected
+        let (port, received) = broker(Some(5)).await;
+        let courses = (0..1000).map(|i| format!("\"K{i}\"")).collect::<Vec<_>>();
+        let (tenant, _dir) = testutil::tenant(
+            "http://127.0.0.1:1",
+            &format!(
+                r#"
+                [[profile]]
+                name = "anna"
+                courses = [{}]
+                "#,
+                courses.join(", ")
+            ),
+        );
+        tenant
+            .data
+            .insert(tenant.default_key(), ArcShift::new(data(false)));
+        tokio::spawn(run(config(port), Arc::new(vec![Arc::new(tenant)])));
+
+        let last = "untis/test/anna/course/K999/cancelled";
+        wait_for(&received, |r| {
+            r.connects.len() == 2 && r.last(last).is_some()
+        })
+        .await;
+        let received = received.lock().unwrap();
+        let online = received
+            .publishes
+            .iter()
+            .skip(5)
+            .filter(|p| p.topic == "untis/status")
+            .count();
+        assert_eq!(online, 1);
+    }
+}
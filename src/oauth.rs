@@ -0,0 +1,561 @@
+use std::{collections::HashMap, fmt::Display};
+
+use reqwest::Url;
+
+use crate::config::OAuthConfig;
+
+pub const FORM_NAME: &str = "iserv_oauth_server_authorize_form";
+
+/// Parameters of the authorize request that are copied from the query if the form
+/// does not carry them itself
+const QUERY_FIELDS: [&str; 6] = [
+    "client_id",
+    "response_type",
+    "redirect_uri",
+    "state",
+    "scope",
+    "nonce",
+];
+const REQUIRED_FIELDS: [&str; 4] = ["client_id", "redirect_uri", "state", "_token"];
+
+#[derive(Debug, PartialEq)]
+pub enum OAuthError {
+    InvalidUrl(String),
+    MissingForm,
+    MissingField(String),
+}
+
+impl Display for OAuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OAuthError::InvalidUrl(url) => write!(f, "authorize page has an invalid url: {url}"),
+            OAuthError::MissingForm => write!(f, "authorize page contains no {FORM_NAME}"),
+            OAuthError::MissingField(name) => {
+                write!(f, "authorize form is missing {FORM_NAME}[{name}]")
+            }
+        }
+    }
+}
+
+impl std::error::Error for OAuthError {}
+
+#[derive(Debug)]
+pub struct AuthorizeForm {
+    /// Where the form is submitted to
+    pub action: Url,
+    pub params: Vec<(String, String)>,
+}
+
+/// A start or end tag with its decoded attributes
+#[derive(Debug)]
+pub struct Tag {
+    pub name: String,
+    pub closing: bool,
+    pub attrs: HashMap<String, String>,
+}
+
+impl Tag {
+    pub fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.get(name).map(String::as_str)
+    }
+}
+
+/// Reads the authorize form from the consent page at `url`. Hidden fields of the form
+/// win over query parameters of the url, `fallback` is only used for what neither has.
+pub fn parse_authorize_form(
+    url: &str,
+    html: &str,
+    fallback: Option<&OAuthConfig>,
+) -> Result<AuthorizeForm, OAuthError> {
+    let page = Url::parse(url).map_err(|_| OAuthError::InvalidUrl(url.to_owned()))?;
+    let form = find_form(html, |tag, inputs| {
+        tag.attr("name") == Some(FORM_NAME)
+            || inputs
+                .iter()
+                .any(|i| i.attr("name").is_some_and(|n| n.starts_with(FORM_NAME)))
+    })
+    .ok_or(OAuthError::MissingForm)?;
+
+    let mut params = form.fields;
+    let has = |params: &Vec<(String, String)>, name: &str| {
+        params.iter().any(|(k, _)| k == &field_name(name))
+    };
+    let query = page.query_pairs().collect::<HashMap<_, _>>();
+    for name in QUERY_FIELDS {
+        if has(&params, name) {
+            continue;
+        }
+        let value = query
+            .get(name)
+            .map(|v| v.to_string())
+            .or_else(|| match (name, fallback) {
+                ("client_id", Some(f)) => f.client_id.clone(),
+                ("redirect_uri", Some(f)) => f.redirect_uri.clone(),
+                ("scope", Some(f)) => f.scope.clone(),
+                ("response_type", _) => Some("code".to_owned()),
+                _ => None,
+            });
+        if let Some(value) = value {
+            params.push((field_name(name), value));
+        }
+    }
+    for name in REQUIRED_FIELDS {
+        if !has(&params, name) {
+            return Err(OAuthError::MissingField(name.to_owned()));
+        }
+    }
+    if !params.iter().any(|(k, _)| k == "accepted") {
+        params.push(("accepted".to_owned(), String::new()));
+    }
+
+    let action = match form.action {
+        Some(a) if !a.is_empty() => page
+            .join(&a)
+            .map_err(|_| OAuthError::InvalidUrl(a.clone()))?,
+        _ => page,
+    };
+    Ok(AuthorizeForm { action, params })
+}
+
+pub fn field_name(name: &str) -> String {
+    format!("{FORM_NAME}[{name}]")
+}
+
+pub struct Form {
+    pub action: Option<String>,
+    /// Hidden inputs in document order
+    pub fields: Vec<(String, String)>,
+    /// Every named input of the form, including the hidden ones
+    pub inputs: Vec<Input>,
+}
+
+pub struct Input {
+    pub name: String,
+    /// Lowercased `type` attribute, `text` if it is missing
+    pub kind: String,
+}
+
+impl Form {
+    pub fn has_input(&self, kind: &str) -> bool {
+        self.inputs.iter().any(|i| i.kind == kind)
+    }
+}
+
+/// Returns the first form for which `matches` is true, given its start tag and inputs
+pub fn find_form(html: &str, matches: impl Fn(&Tag, &[&Tag]) -> bool) -> Option<Form> {
+    let tags = tags(html).collect::<Vec<_>>();
+    let mut i = 0;
+    while i < tags.len() {
+        if tags[i].name != "form" || tags[i].closing {
+            i += 1;
+            continue;
+        }
+        let end = tags[i + 1..]
+            .iter()
+            .position(|t| t.name == "form")
+            .map_or(tags.len(), |p| i + 1 + p);
+        let inner = &tags[i + 1..end];
+        let inputs = inner
+            .iter()
+            .filter(|t| !t.closing && matches!(t.name.as_str(), "input" | "button"))
+            .collect::<Vec<_>>();
+        if matches(&tags[i], &inputs) {
+            let fields = inputs
+                .iter()
+                .filter(|t| {
+                    t.attr("type")
+                        .is_some_and(|k| k.eq_ignore_ascii_case("hidden"))
+                })
+                .filter_map(|t| {
+                    let name = t.attr("name")?;
+                    Some((name.to_owned(), t.attr("value").unwrap_or("").to_owned()))
+                })
+                .collect();
+            return Some(Form {
+                action: tags[i].attr("action").map(str::to_owned),
+                fields,
+                inputs: inputs
+                    .iter()
+                    .filter(|t| t.name == "input")
+                    .filter_map(|t| {
+                        Some(Input {
+                            name: t.attr("name")?.to_owned(),
+                            kind: t.attr("type").unwrap_or("text").to_ascii_lowercase(),
+                        })
+                    })
+                    .collect(),
+            });
+        }
+        i = end + 1;
+    }
+    None
+}
+
+/// Iterates over all tags of a document. Comments, scripts and styles are skipped,
+/// attribute values are entity decoded and attribute names lowercased.
+pub fn tags(html: &str) -> impl Iterator<Item = Tag> + '_ {
+    let mut pos = 0;
+    std::iter::from_fn(move || loop {
+        let start = pos + html.get(pos..)?.find('<')?;
+        let rest = &html[start..];
+        if rest.starts_with("<!--") {
+            pos = start + rest.find("-->").map_or(rest.len(), |e| e + 3);
+            continue;
+        }
+        let (tag, len) = parse_tag(rest);
+        pos = start + len;
+        if !tag.closing && matches!(tag.name.as_str(), "script" | "style") {
+            let close = format!("</{}", tag.name);
+            let lower = html[pos..].to_ascii_lowercase();
+            pos += lower.find(&close).unwrap_or(lower.len());
+        }
+        if tag.name.is_empty() {
+            continue;
+        }
+        return Some(tag);
+    })
+}
+
+/// Parses the tag at the start of `s`, returns it and the number of bytes it spans
+fn parse_tag(s: &str) -> (Tag, usize) {
+    let bytes = s.as_bytes();
+    let mut i = 1;
+    let closing = bytes.get(i) == Some(&b'/');
+    if closing {
+        i += 1;
+    }
+    let name_start = i;
+    while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'-') {
+        i += 1;
+    }
+    let name = s[name_start..i].to_ascii_lowercase();
+    let mut attrs = HashMap::new();
+    if name.is_empty() && bytes.get(1) != Some(&b'!') {
+        // a literal `<` in text
+        return (
+            Tag {
+                name,
+                closing,
+                attrs,
+            },
+            1,
+        );
+    }
+    loop {
+        while i < bytes.len() && (bytes[i].is_ascii_whitespace() || bytes[i] == b'/') {
+            i += 1;
+        }
+        if i >= bytes.len() {
+            // unterminated tag, treat the rest of the document as its content
+            break;
+        }
+        if bytes[i] == b'>' {
+            i += 1;
+            break;
+        }
+        let key_start = i;
+        while i < bytes.len()
+            && !bytes[i].is_ascii_whitespace()
+            && !matches!(bytes[i], b'=' | b'>' | b'/')
+        {
+            i += 1;
+        }
+        let key = s[key_start..i].to_ascii_lowercase();
+        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        let mut value = String::new();
+        if bytes.get(i) == Some(&b'=') {
+            i += 1;
+            while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            match bytes.get(i) {
+                Some(q @ (b'"' | b'\'')) => {
+                    let end = s[i + 1..].find(*q as char).map_or(s.len(), |e| i + 1 + e);
+                    value = decode_entities(&s[i + 1..end]);
+                    i = (end + 1).min(s.len());
+                }
+                Some(_) => {
+                    let value_start = i;
+                    while i < bytes.len() && !bytes[i].is_ascii_whitespace() && bytes[i] != b'>' {
+                        i += 1;
+                    }
+                    value = decode_entities(&s[value_start..i]);
+                }
+                None => {}
+            }
+        }
+        if key.is_empty() {
+            // a value without a name like `<a =x>`, the `=` was consumed above so the
+            // loop still advances and a following `>` ends the tag
+            continue;
+        }
+        attrs.entry(key).or_insert(value);
+    }
+    (
+        Tag {
+            name,
+            closing,
+            attrs,
+        },
+        i,
+    )
+}
+
+/// Target of a `<meta http-equiv="refresh" content="0;url=...">` tag
+pub fn meta_refresh(html: &str) -> Option<String> {
+    tags(html)
+        .filter(|t| t.name == "meta")
+        .filter(|t| {
+            t.attr("http-equiv")
+                .is_some_and(|v| v.eq_ignore_ascii_case("refresh"))
+        })
+        .find_map(|t| {
+            let content = t.attr("content")?;
+            let start = content.to_ascii_lowercase().find("url=")? + 4;
+            Some(content[start..].trim().trim_matches(['\'', '"']).to_owned())
+        })
+}
+
+/// Decodes named entities common in attribute values and all numeric references
+pub fn decode_entities(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let Some(semi) = rest.bytes().take(12).position(|b| b == b';') else {
+            out.push('&');
+            rest = &rest[1..];
+            continue;
+        };
+        let entity = &rest[1..semi];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "nbsp" => Some('\u{a0}'),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|d| d.parse().ok()))
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[semi + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    const AUTHORIZE_URL: &str = "https://iserv.example.org/iserv/oauth/v2/auth?client_id=untis&redirect_uri=https%3A%2F%2Fnessa.webuntis.com%2FWebUntis%2Foidc%2Fcallback&response_type=code&scope=openid+profile&state=c3RhdGU%3D&nonce=bm9u+Y2U%3D";
+
+    const AUTHORIZE_PAGE: &str = r#"<!DOCTYPE html>
+<html lang="de">
+<head>
+  <script>if (a < b && c > d) { document.write('<form name="fake">'); }</script>
+  <!-- <form name="iserv_oauth_server_authorize_form" action="/commented"> -->
+</head>
+<body>
+  <form class="search" action="/iserv/search"><input type="text" name="q"></form>
+  <form name="iserv_oauth_server_authorize_form" method="post" action="/iserv/oauth/v2/auth/consent">
+    <input type="hidden" id="f_token" name="iserv_oauth_server_authorize_form[_token]" value="tok&amp;en">
+    <input type="hidden" name="iserv_oauth_server_authorize_form[client_id]" value='form-client'>
+    <button type="submit" name="accepted">Zulassen</button>
+  </form>
+</body>
+</html>"#;
+
+    fn param<'a>(form: &'a AuthorizeForm, name: &str) -> Option<&'a str> {
+        let name = field_name(name);
+        form.params
+            .iter()
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    #[test]
+    fn authorize_form_of_iserv() {
+        let form = parse_authorize_form(AUTHORIZE_URL, AUTHORIZE_PAGE, None).unwrap();
+        assert_eq!(
+            form.action.as_str(),
+            "https://iserv.example.org/iserv/oauth/v2/auth/consent"
+        );
+        assert_eq!(param(&form, "_token"), Some("tok&en"));
+        // hidden fields win over the query
+        assert_eq!(param(&form, "client_id"), Some("form-client"));
+        // percent and plus encoding of the query is decoded
+        assert_eq!(param(&form, "state"), Some("c3RhdGU="));
+        assert_eq!(param(&form, "nonce"), Some("bm9u Y2U="));
+        assert_eq!(param(&form, "scope"), Some("openid profile"));
+        assert_eq!(
+            param(&form, "redirect_uri"),
+            Some("https://nessa.webuntis.com/WebUntis/oidc/callback")
+        );
+        assert!(form.params.iter().any(|(k, _)| k == "accepted"));
+    }
+
+    #[test]
+    fn authorize_form_uses_fallback_last() {
+        let page = r#"<form action=""><input type=hidden name="iserv_oauth_server_authorize_form[_token]" value=t></form>"#;
+        let fallback = OAuthConfig {
+            client_id: Some("fallback".to_owned()),
+            redirect_uri: Some("https://untis/callback".to_owned()),
+            scope: None,
+        };
+        let url = "https://iserv.example.org/auth?state=s&client_id=query";
+        let form = parse_authorize_form(url, page, Some(&fallback)).unwrap();
+        assert_eq!(form.action.as_str(), url);
+        assert_eq!(param(&form, "client_id"), Some("query"));
+        assert_eq!(param(&form, "redirect_uri"), Some("https://untis/callback"));
+        assert_eq!(param(&form, "response_type"), Some("code"));
+        assert_eq!(param(&form, "scope"), None);
+    }
+
+    #[test]
+    fn authorize_form_errors() {
+        assert_eq!(
+            parse_authorize_form("not a url", AUTHORIZE_PAGE, None).unwrap_err(),
+            OAuthError::InvalidUrl("not a url".to_owned())
+        );
+        assert_eq!(
+            parse_authorize_form(
+                AUTHORIZE_URL,
+                r#"<form action="/login"><input name="_username"></form>"#,
+                None
+            )
+            .unwrap_err(),
+            OAuthError::MissingForm
+        );
+        let without_state = AUTHORIZE_URL.replace("state=", "other=");
+        assert_eq!(
+            parse_authorize_form(&without_state, AUTHORIZE_PAGE, None).unwrap_err(),
+            OAuthError::MissingField("state".to_owned())
+        );
+        let without_token = AUTHORIZE_PAGE.replace("[_token]", "[other]");
+        assert_eq!(
+            parse_authorize_form(AUTHORIZE_URL, &without_token, None).unwrap_err(),
+            OAuthError::MissingField("_token".to_owned())
+        );
+    }
+
+    #[test]
+    fn find_form_reports_inputs() {
+        let html = r#"<FORM Action="/a"><input type="hidden" name="h" value="1"><INPUT TYPE="Password" name="p"><input name="u"></form>
+            <form action="/b"><input type="hidden" name="other"></form>"#;
+        let form = find_form(html, |_, _| true).unwrap();
+        assert_eq!(form.action.as_deref(), Some("/a"));
+        assert_eq!(form.fields, vec![("h".to_owned(), "1".to_owned())]);
+        assert!(form.has_input("password"));
+        assert!(form.has_input("text"));
+        assert_eq!(form.inputs.len(), 3);
+
+        let second = find_form(html, |tag, _| tag.attr("action") == Some("/b")).unwrap();
+        assert_eq!(second.fields, vec![("other".to_owned(), String::new())]);
+        assert!(find_form(html, |tag, _| tag.attr("action") == Some("/c")).is_none());
+        assert!(find_form("<p>no form</p>", |_, _| true).is_none());
+    }
+
+    #[test]
+    fn tag_attributes() {
+        let html = r#"<Input TYPE=hidden name='a b' value="x &lt; y" disabled/>rest"#;
+        let (tag, len) = parse_tag(html);
+        assert_eq!(tag.name, "input");
+        assert!(!tag.closing);
+        assert_eq!(tag.attr("type"), Some("hidden"));
+        assert_eq!(tag.attr("name"), Some("a b"));
+        assert_eq!(tag.attr("value"), Some("x < y"));
+        assert_eq!(tag.attr("disabled"), Some(""));
+        assert_eq!(&html[len..], "rest");
+
+        let (tag, len) = parse_tag("</form >");
+        assert_eq!(tag.name, "form");
+        assert!(tag.closing);
+        assert_eq!(len, 8);
+
+        let (tag, len) = parse_tag("< b");
+        assert!(tag.name.is_empty());
+        assert_eq!(len, 1);
+    }
+
+    #[test]
+    fn tag_with_nameless_value_ends_at_its_bracket() {
+        let (tag, len) = parse_tag("<a =x>text name=y>");
+        assert_eq!(tag.name, "a");
+        assert!(tag.attrs.is_empty());
+        assert_eq!(len, 6);
+
+        let names = tags("<a =x><b c=d>").map(|t| t.name).collect::<Vec<_>>();
+        assert_eq!(names, ["a", "b"]);
+    }
+
+    #[test]
+    fn meta_refresh_target() {
+        let html = r#"<html><head><meta charset="utf-8"><META HTTP-EQUIV="Refresh" content="0; URL='https://nessa.webuntis.com/WebUntis/?a=1&amp;b=2'"></head></html>"#;
+        assert_eq!(
+            meta_refresh(html).as_deref(),
+            Some("https://nessa.webuntis.com/WebUntis/?a=1&b=2")
+        );
+        assert_eq!(
+            meta_refresh(r#"<meta http-equiv="refresh" content="5">"#),
+            None
+        );
+        assert_eq!(meta_refresh("<p>nothing</p>"), None);
+    }
+
+    #[test]
+    fn entities() {
+        assert_eq!(decode_entities("a &amp; b"), "a & b");
+        assert_eq!(decode_entities("&quot;&apos;&lt;&gt;&nbsp;"), "\"'<>\u{a0}");
+        assert_eq!(decode_entities("&#61;&#x3D;&#X3d;"), "===");
+        assert_eq!(
+            decode_entities("&unknown; & &#xZZ; &"),
+            "&unknown; & &#xZZ; &"
+        );
+        assert_eq!(decode_entities("&#1114112;"), "&#1114112;");
+        assert_eq!(
+            decode_entities("&verylongentityname;"),
+            "&verylongentityname;"
+        );
+        assert_eq!(decode_entities("ü&amp;ö"), "ü&ö");
+    }
+
+    proptest! {
+        #[test]
+        fn arbitrary_input_never_panics(html in "(<|>|/|=|!|-|\"|'|&|;|#|x|a|form|input|script|ü| |\\PC){0,64}") {
+            let mut pos = 0;
+            let mut rest = html.as_str();
+            while let Some(start) = rest.find('<') {
+                let (_, len) = parse_tag(&rest[start..]);
+                // every tag spans at least its `<`, so `tags` always progresses
+                prop_assert!(len > 0);
+                prop_assert!(start + len <= rest.len());
+                pos += start + len;
+                rest = &html[pos..];
+            }
+            prop_assert!(tags(&html).count() <= html.matches('<').count());
+            decode_entities(&html);
+            meta_refresh(&html);
+            find_form(&html, |_, _| true);
+            parse_authorize_form(AUTHORIZE_URL, &html, None).ok();
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
+use serde::Serialize;
+
+use crate::config::PollingConfig;
+
+/// Part of the week that decides how often upstream is polled
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Period {
+    /// School morning, changes for the day come in
+    Peak,
+    Day,
+    Night,
+    /// Weekend or holiday
+    FreeDay,
+}
+
+impl Period {
+    /// Whether upstream requests go through the lower off-peak rate limit
+    pub fn is_off_peak(self) -> bool {
+        matches!(self, Period::Night | Period::FreeDay)
+    }
+
+    /// Factor the tier intervals are multiplied with
+    pub fn factor(self, config: &PollingConfig) -> f64 {
+        match self {
+            Period::Peak => config.peak_factor,
+            Period::Day => 1.0,
+            Period::Night => config.night_factor,
+            Period::FreeDay => config.free_day_factor,
+        }
+    }
+}
+
+/// Period at `now`. `no_lessons` tells whether the day is known to have no lessons.
+pub fn period(config: &PollingConfig, now: NaiveDateTime, no_lessons: bool) -> Period {
+    let day = now.date();
+    let holiday = config
+        .holidays
+        .iter()
+        .any(|(first, last)| *first <= day && day <= *last);
+    if holiday || no_lessons || matches!(day.weekday(), Weekday::Sat | Weekday::Sun) {
+        Period::FreeDay
+    } else if contains(config.night, now.time()) {
+        Period::Night
+    } else if contains(config.peak, now.time()) {
+        Period::Peak
+    } else {
+        Period::Day
+    }
+}
+
+/// Whether `time` lies in the span, which may wrap around midnight
+fn contains((start, end): (NaiveTime, NaiveTime), time: NaiveTime) -> bool {
+    if start <= end {
+        start <= time && time < end
+    } else {
+        time >= start || time < end
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::BTreeMap;
+
+use chrono::NaiveDate;
+use serde::Serialize;
+
+use crate::{
+    config::SanityConfig,
+    definitions::CalendarEntry,
+    fetch::{unix_now, DayResult},
+};
+
+/// Fetched days that were not applied because too many lessons vanished
+#[derive(Clone, Serialize)]
+pub struct HeldUpdate {
+    pub tier: usize,
+    pub first: NaiveDate,
+    pub last: NaiveDate,
+    pub lessons_before: usize,
+    pub lessons_after: usize,
+    /// Unix timestamp of the fetch that was held back
+    pub since: u64,
+    pub reason: String,
+}
+
+/// Compares the lessons of the fetched days with what is served now. Returns why the
+/// update looks suspicious, `None` if it can be applied.
+pub fn check(
+    config: &SanityConfig,
+    entries: &BTreeMap<NaiveDate, Vec<CalendarEntry>>,
+    results: &BTreeMap<NaiveDate, DayResult>,
+) -> Option<(usize, usize, String)> {
+    let mut before = 0;
+    let mut after = 0;
+    for (day, result) in results {
+        if let DayResult::Fetched(lessons) = result {
+            before += entries.get(day).map_or(0, Vec::len);
+            after += lessons.len();
+        }
+    }
+    if before < config.min_lessons || after >= before {
+        return None;
+    }
+    let vanished = (before - after) * 100 / before;
+    (vanished > config.max_vanished_percent as usize).then(|| {
+        (
+            before,
+            after,
+            format!(
+                "{vanished}% of the lessons vanished, more than the allowed {}%",
+                config.max_vanished_percent
+            ),
+        )
+    })
+}
+
+impl HeldUpdate {
+    pub fn new(
+        tier: usize,
+        results: &BTreeMap<NaiveDate, DayResult>,
+        (before, after, reason): (usize, usize, String),
+    ) -> Self {
+        Self {
+            tier,
+            first: results.keys().next().copied().unwrap_or_default(),
+            last: results.keys().next_back().copied().unwrap_or_default(),
+            lessons_before: before,
+            lessons_after: after,
+            since: unix_now(),
+            reason,
+        }
+    }
+}
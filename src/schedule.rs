@@ -0,0 +1,101 @@
+use std::time::Duration;
+
+use chrono::{Days, NaiveDate};
+use tokio::time::Instant;
+
+use crate::{
+    breaker::{backoff, jitter},
+    config::{SchoolConfig, TierConfig},
+};
+
+/// Pause before a tier whose fetch failed is tried again, doubled for each further
+/// failure in a row
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30 * 60);
+
+/// First and last day of the timetable window of a school
+pub fn window(school: &SchoolConfig, today: NaiveDate) -> (NaiveDate, NaiveDate) {
+    let start = today.week(chrono::Weekday::Mon).first_day() - Days::new(school.negative_offset);
+    let end = start + Days::new(school.negative_offset + school.positive_offset as u64 - 1);
+    (start, end)
+}
+
+/// Decides which slice of the window is fetched when. Every tier is due right away
+/// after creation, afterwards each one runs at its own interval.
+#[derive(Clone)]
+pub struct Schedule {
+    tiers: Vec<TierConfig>,
+    due: Vec<Instant>,
+    fetched: Vec<bool>,
+    failures: Vec<u32>,
+}
+
+impl Schedule {
+    pub fn new(tiers: &[TierConfig]) -> Self {
+        let now = Instant::now();
+        Self {
+            tiers: tiers.to_vec(),
+            due: vec![now; tiers.len()],
+            fetched: vec![false; tiers.len()],
+            failures: vec![0; tiers.len()],
+        }
+    }
+
+    /// The tier that is due next and when, nearer tiers win ties
+    pub fn next(&self) -> (usize, Instant) {
+        self.due
+            .iter()
+            .copied()
+            .enumerate()
+            .min_by_key(|(_, at)| *at)
+            .expect("config validation ensures at least one tier")
+    }
+
+    /// Schedules the next run of `tier`, its interval scaled by the polling `factor`
+    pub fn done(&mut self, tier: usize, factor: f64) {
+        let interval = Duration::from_secs(self.tiers[tier].interval).mul_f64(factor);
+        self.due[tier] = Instant::now() + interval;
+        self.fetched[tier] = true;
+        self.failures[tier] = 0;
+    }
+
+    /// Retries `tier` with exponential backoff and jitter
+    pub fn failed(&mut self, tier: usize) {
+        self.failures[tier] += 1;
+        let delay = backoff(RETRY_DELAY, self.failures[tier], MAX_RETRY_DELAY);
+        self.due[tier] = Instant::now() + jitter(delay);
+    }
+
+    /// Whether every tier was fetched at least once, so the data covers the window
+    pub fn is_complete(&self) -> bool {
+        self.fetched.iter().all(|f| *f)
+    }
+
+    /// Day ranges of `tier` inside `window`, both ends inclusive
+    pub fn slices(
+        &self,
+        tier: usize,
+        window: (NaiveDate, NaiveDate),
+        today: NaiveDate,
+    ) -> Vec<(NaiveDate, NaiveDate)> {
+        let (first, last) = window;
+        let from = match tier {
+            0 => 0,
+            _ => self.tiers[tier - 1].days.unwrap_or_default(),
+        };
+        let start = (today + Days::new(from)).max(first);
+        let end = match self.tiers[tier].days {
+            Some(days) => (today + Days::new(days - 1)).min(last),
+            None => last,
+        };
+        let mut slices = Vec::new();
+        if self.tiers[tier].days.is_none() && first < today {
+            // the past belongs to the last tier
+            slices.push((first, (today - Days::new(1)).min(last)));
+        }
+        if start <= end {
+            slices.push((start, end));
+        }
+        slices
+    }
+}
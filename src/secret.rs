@@ -0,0 +1,117 @@
+use std::{
+    fmt::{Debug, Display},
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+/// A secret value. It never shows up in `Debug` or `Display` output, so it can not
+/// leak into logs by accident; use `expose` where the value is really needed.
+#[derive(Clone, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+impl Display for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+/// Where a secret is read from, written as e.g. `{ env = "PASSWORD" }` in the config
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub enum SecretRef {
+    /// Environment variable. If it is unset, `<NAME>_FILE` may name a file with the
+    /// value, and a systemd credential called `<NAME>` is tried last.
+    Env(String),
+    File(PathBuf),
+    /// Credential passed in by systemd with `LoadCredential=`
+    Credential(String),
+    /// The value itself, only meant for testing
+    Value(Secret),
+}
+
+#[derive(Debug)]
+pub enum SecretError {
+    Missing(String),
+    Io(PathBuf, std::io::Error),
+    NoCredentialsDirectory(String),
+}
+
+impl Display for SecretError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecretError::Missing(name) => write!(
+                f,
+                "${name} is not set, neither is ${name}_FILE or a credential called {name}"
+            ),
+            SecretError::Io(path, e) => write!(f, "could not read {}: {e}", path.display()),
+            SecretError::NoCredentialsDirectory(name) => write!(
+                f,
+                "credential {name} requested, but $CREDENTIALS_DIRECTORY is not set"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SecretError {}
+
+impl SecretRef {
+    pub fn env(name: &str) -> Self {
+        SecretRef::Env(name.to_owned())
+    }
+
+    /// Reads the secret. Files are read on every call, so rotated secrets are picked up.
+    pub fn resolve(&self) -> Result<Secret, SecretError> {
+        match self {
+            SecretRef::Env(name) => {
+                if let Ok(value) = std::env::var(name) {
+                    return Ok(Secret(value));
+                }
+                if let Ok(path) = std::env::var(format!("{name}_FILE")) {
+                    return read_file(Path::new(&path));
+                }
+                match credentials_directory() {
+                    Some(dir) if dir.join(name).is_file() => read_file(&dir.join(name)),
+                    _ => Err(SecretError::Missing(name.clone())),
+                }
+            }
+            SecretRef::File(path) => read_file(path),
+            SecretRef::Credential(name) => {
+                let dir = credentials_directory()
+                    .ok_or(SecretError::NoCredentialsDirectory(name.clone()))?;
+                read_file(&dir.join(name))
+            }
+            SecretRef::Value(secret) => Ok(secret.clone()),
+        }
+    }
+}
+
+fn credentials_directory() -> Option<PathBuf> {
+    std::env::var_os("CREDENTIALS_DIRECTORY").map(PathBuf::from)
+}
+
+/// Reads a secret file, a single trailing newline is not part of the secret
+fn read_file(path: &Path) -> Result<Secret, SecretError> {
+    let mut value =
+        std::fs::read_to_string(path).map_err(|e| SecretError::Io(path.to_owned(), e))?;
+    if value.ends_with('\n') {
+        value.pop();
+        if value.ends_with('\r') {
+            value.pop();
+        }
+    }
+    Ok(Secret(value))
+}
@@ -0,0 +1,216 @@
+use std::time::{Duration, Instant};
+
+use tokio::sync::{watch, Mutex};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    auth::{login, try_refresh, AuthProvider, Credentials},
+    config::SchoolConfig,
+    error::Error,
+    jwt::Claims,
+    lockout::{LoginGuard, LoginStatus, Verdict},
+    state::SessionStore,
+};
+
+/// Sessions without readable claims are refreshed after this long
+const SESSION_MAX_AGE: Duration = Duration::from_secs(300);
+/// Pause before the refresher tries again after a failed login
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+pub struct Session {
+    pub token: String,
+    pub cookies: String,
+    pub claims: Option<Claims>,
+    obtained: Instant,
+    stale: bool,
+}
+
+impl Session {
+    fn new(token: String, cookies: String) -> Self {
+        Self {
+            claims: Claims::decode(&token),
+            token,
+            cookies,
+            obtained: Instant::now(),
+            stale: false,
+        }
+    }
+
+    fn refresh_in(&self) -> Duration {
+        if self.stale {
+            return Duration::ZERO;
+        }
+        match &self.claims {
+            Some(c) => c.refresh_in(),
+            None => SESSION_MAX_AGE.saturating_sub(self.obtained.elapsed()),
+        }
+    }
+}
+
+/// Owns the one authenticated session of a tenant. The lock is held while logging in,
+/// so concurrent fetch tasks wait for the running login instead of starting their own.
+pub struct SessionManager {
+    current: Mutex<Option<Session>>,
+    /// Claims of the current session, readable while a login holds the lock
+    claims: watch::Sender<Option<Claims>>,
+    store: SessionStore,
+    guard: LoginGuard,
+}
+
+impl SessionManager {
+    pub fn new(store: SessionStore, guard: LoginGuard) -> Self {
+        Self {
+            current: Mutex::new(None),
+            claims: watch::Sender::new(None),
+            store,
+            guard,
+        }
+    }
+
+    pub async fn get(
+        &self,
+        school: &SchoolConfig,
+        provider: &dyn AuthProvider,
+    ) -> Result<Session, Error> {
+        let mut current = self.current.lock().await;
+        if current.is_none() {
+            *current = self.restore(school);
+            self.claims
+                .send_replace(current.as_ref().and_then(|s| s.claims.clone()));
+        }
+        if let Some(session) = current.as_ref() {
+            if !session.refresh_in().is_zero() {
+                return Ok(session.clone());
+            }
+        }
+        let refreshed = match current.as_ref() {
+            Some(s) => try_refresh(school, s.cookies.clone())
+                .await
+                .inspect_err(|e| debug!("Session of {} not refreshed: {e}", school.id))
+                .ok(),
+            None => None,
+        };
+        let (token, cookies) = match refreshed {
+            Some(s) => {
+                info!("Session could be recovered");
+                s
+            }
+            None => self.login(school, provider).await?,
+        };
+        self.store.save(&token, &cookies);
+        let session = Session::new(token, cookies);
+        match &session.claims {
+            Some(c) => info!(
+                "Session for {} renewed, person {:?}, klasse {:?}, valid for {}s",
+                school.id,
+                c.person_id,
+                c.klasse_id,
+                c.refresh_in().as_secs()
+            ),
+            None => warn!(
+                "Session for {} renewed, but the token has no claims",
+                school.id
+            ),
+        }
+        self.claims.send_replace(session.claims.clone());
+        *current = Some(session.clone());
+        Ok(session)
+    }
+
+    /// Full login, unless the guard holds it back after rejected credentials
+    async fn login(
+        &self,
+        school: &SchoolConfig,
+        provider: &dyn AuthProvider,
+    ) -> Result<(String, String), Error> {
+        let credentials = Credentials::resolve(&school.credentials)
+            .inspect_err(|e| error!("Login for {} failed: {e}", school.id))?;
+        let fingerprint = self.guard.fingerprint(school, &credentials);
+        match self.guard.check(&fingerprint) {
+            Verdict::Allowed => {}
+            Verdict::Backoff(at) => {
+                debug!("Login for {} held back until {at}", school.id);
+                return Err(Error::LoginBlocked(format!("backing off until {at}")));
+            }
+            Verdict::Locked => {
+                debug!("Login for {} is locked", school.id);
+                return Err(Error::LoginBlocked(
+                    "locked after rejected logins".to_owned(),
+                ));
+            }
+        }
+        let result = login(school, provider, &credentials).await;
+        self.guard.record(&fingerprint, &result);
+        Ok(result.inspect_err(|e| error!("Login for {} failed: {e}", school.id))?)
+    }
+
+    pub fn login_status(&self) -> LoginStatus {
+        self.guard.status()
+    }
+
+    pub fn reset_login(&self) {
+        self.guard.reset()
+    }
+
+    /// Loads the saved session. Its cookies are used for the first refresh, the token
+    /// itself only if it is not about to expire.
+    fn restore(&self, school: &SchoolConfig) -> Option<Session> {
+        let stored = self.store.load()?;
+        info!("Trying saved session for {}", school.id);
+        let mut session = Session::new(stored.token, stored.cookies);
+        session.stale = session.claims.as_ref().is_none_or(|c| c.is_expired());
+        Some(session)
+    }
+
+    /// Marks the session as unusable, the next `get` refreshes it. Does nothing if
+    /// the session was already replaced since `token` was handed out.
+    pub async fn invalidate(&self, token: &str) {
+        let mut current = self.current.lock().await;
+        if let Some(session) = current.as_mut() {
+            if session.token == token {
+                session.stale = true;
+            }
+        }
+    }
+
+    pub fn claims(&self) -> Option<Claims> {
+        self.claims.borrow().clone()
+    }
+
+    /// Refreshes the session shortly before the token expires, so fetch tasks never
+    /// have to wait for a login
+    pub async fn keep_fresh(&self, school: &SchoolConfig, provider: &dyn AuthProvider) {
+        loop {
+            let wait = match self.current.lock().await.as_ref() {
+                Some(s) => s.refresh_in(),
+                None => Duration::ZERO,
+            };
+            tokio::time::sleep(wait).await;
+            if let Err(e) = self.get(school, provider).await {
+                warn!("Refreshing the session for {} failed: {e}", school.id);
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testutil;
+
+    #[tokio::test]
+    async fn claims_are_readable_during_a_login() {
+        let addr = testutil::serve(|req| testutil::webuntis(&req).unwrap()).await;
+        let (tenant, _dir) = testutil::tenant(&format!("http://{addr}"), "");
+        assert!(tenant.claims().is_none());
+
+        let session = tenant.session().await.unwrap();
+        let exp = session.claims.unwrap().exp;
+        assert_eq!(tenant.claims().unwrap().exp, exp);
+
+        // a running login holds the lock
+        let _current = tenant.sessions.current.lock().await;
+        assert_eq!(tenant.claims().unwrap().exp, exp);
+    }
+}
@@ -0,0 +1,181 @@
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Sender},
+};
+
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{definitions::CalendarEntry, fetch::unix_now, fetch::DayStatus};
+
+/// Bumped whenever the layout changes, older snapshots are ignored
+const VERSION: u32 = 1;
+
+type Entries = BTreeMap<NaiveDate, Vec<CalendarEntry>>;
+type Days = BTreeMap<NaiveDate, DayStatus>;
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot<'a> {
+    version: u32,
+    /// Unix timestamp
+    saved_at: u64,
+    entries: Cow<'a, Entries>,
+    days: Cow<'a, Days>,
+}
+
+/// Last served lessons of each element of a tenant, so calendars are not empty after
+/// a restart while the first fetch is still running
+pub struct SnapshotStore {
+    dir: PathBuf,
+    tenant: String,
+    /// Snapshots are written on their own thread, in the order they were saved
+    writer: Sender<(PathBuf, Snapshot<'static>)>,
+}
+
+impl SnapshotStore {
+    pub fn new(state_dir: &Path, tenant: &str) -> Self {
+        let (writer, snapshots) = mpsc::channel::<(PathBuf, Snapshot)>();
+        std::thread::spawn(move || {
+            for (path, snapshot) in snapshots {
+                if let Err(e) = write(&path, &snapshot) {
+                    warn!("Could not save snapshot to {}: {e}", path.display());
+                }
+            }
+        });
+        Self {
+            dir: state_dir.to_owned(),
+            tenant: tenant.to_owned(),
+            writer,
+        }
+    }
+
+    fn path(&self, element: isize) -> PathBuf {
+        self.dir.join(format!("{}.{element}.snapshot", self.tenant))
+    }
+
+    pub fn load(&self, element: isize) -> Option<(Entries, Days)> {
+        let path = self.path(element);
+        let bytes = fs::read(&path).ok()?;
+        match serde_json::from_slice::<Snapshot>(&bytes) {
+            Ok(snapshot) if snapshot.version == VERSION => {
+                info!(
+                    "Loaded snapshot of {element} from {}s ago",
+                    unix_now().saturating_sub(snapshot.saved_at)
+                );
+                Some((snapshot.entries.into_owned(), snapshot.days.into_owned()))
+            }
+            Ok(_) => {
+                info!("Ignoring snapshot {} of an older version", path.display());
+                None
+            }
+            Err(e) => {
+                warn!("Could not read snapshot {}: {e}", path.display());
+                None
+            }
+        }
+    }
+
+    /// Queues a snapshot that replaces the one of `element`
+    pub fn save(&self, element: isize, entries: Entries, days: Days) {
+        let snapshot = Snapshot {
+            version: VERSION,
+            saved_at: unix_now(),
+            entries: Cow::Owned(entries),
+            days: Cow::Owned(days),
+        };
+        self.writer.send((self.path(element), snapshot)).ok();
+    }
+}
+
+/// Writes `snapshot` next to `path` and moves it there once it is on disk, a crash
+/// while writing keeps the old one
+fn write(path: &Path, snapshot: &Snapshot) -> std::io::Result<()> {
+    let json = serde_json::to_vec(snapshot)?;
+    let tmp = path.with_extension("snapshot.tmp");
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    file.write_all(&json)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    // the rename itself is only durable once the directory is synced
+    if let Some(dir) = path.parent() {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn lesson() -> CalendarEntry {
+        CalendarEntry {
+            id: 1,
+            start_date_time: "2026-10-19T08:00".to_owned(),
+            end_date_time: "2026-10-19T08:45".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    /// Polls until the writer thread replaced the snapshot of `element`
+    fn wait_for(store: &SnapshotStore, element: isize) -> (Entries, Days) {
+        for _ in 0..200 {
+            if let Some(snapshot) = store.load(element) {
+                return snapshot;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("snapshot of {element} was not written");
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SnapshotStore::new(dir.path(), "gam");
+        assert!(store.load(-5).is_none());
+
+        let day = NaiveDate::from_ymd_opt(2026, 10, 19).unwrap();
+        let entries = Entries::from([(day, vec![lesson()])]);
+        let days = Days::from([(day, DayStatus::default())]);
+        store.save(-5, entries.clone(), days.clone());
+        let (loaded, loaded_days) = wait_for(&store, -5);
+        assert_eq!(loaded, entries);
+        assert_eq!(loaded_days.keys().collect::<Vec<_>>(), vec![&day]);
+        assert!(!store.path(-5).with_extension("snapshot.tmp").exists());
+        assert!(store.load(-6).is_none());
+    }
+
+    #[test]
+    fn corrupt_and_old_snapshots_are_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SnapshotStore::new(dir.path(), "gam");
+        fs::write(store.path(-5), b"{\"version\": 1, \"saved_at").unwrap();
+        assert!(store.load(-5).is_none());
+
+        let snapshot = |version| {
+            serde_json::json!({
+                "version": version,
+                "saved_at": 0,
+                "entries": {"2026-10-19": [lesson()]},
+                "days": {},
+            })
+            .to_string()
+        };
+        fs::write(store.path(-5), snapshot(VERSION - 1)).unwrap();
+        assert!(store.load(-5).is_none());
+        fs::write(store.path(-5), snapshot(VERSION)).unwrap();
+        assert!(store.load(-5).is_some());
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use cookie::{Cookie, CookieJar, Key};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+const SESSION_COOKIE: &str = "session";
+
+/// Loads the key used to encrypt state files from `<state_dir>/key`, a new one is
+/// generated on first start.
+pub fn load_key(state_dir: &Path) -> std::io::Result<Key> {
+    fs::create_dir_all(state_dir)?;
+    let path = state_dir.join("key");
+    match fs::read(&path) {
+        Ok(bytes) => Key::try_from(bytes.as_slice()).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{}: {e}", path.display()),
+            )
+        }),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            info!("Generating new state key at {}", path.display());
+            let key = Key::generate();
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&path)?
+                .write_all(key.master())?;
+            Ok(key)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct StoredSession {
+    pub token: String,
+    pub cookies: String,
+    /// Unix timestamp of the login or refresh that produced this session
+    pub saved_at: u64,
+}
+
+/// Encrypted session file of one tenant, sealed with the `private` cookie jar
+pub struct SessionStore {
+    path: PathBuf,
+    key: Key,
+}
+
+impl SessionStore {
+    pub fn new(state_dir: &Path, tenant: &str, key: Key) -> Self {
+        Self {
+            path: state_dir.join(format!("{tenant}.session")),
+            key,
+        }
+    }
+
+    pub fn load(&self) -> Option<StoredSession> {
+        let sealed = fs::read_to_string(&self.path).ok()?;
+        let mut jar = CookieJar::new();
+        jar.add_original(Cookie::new(SESSION_COOKIE, sealed.trim().to_owned()));
+        let Some(cookie) = jar.private(&self.key).get(SESSION_COOKIE) else {
+            warn!("Could not decrypt {}, ignoring it", self.path.display());
+            return None;
+        };
+        serde_json::from_str(cookie.value()).ok()
+    }
+
+    pub fn save(&self, token: &str, cookies: &str) {
+        let session = StoredSession {
+            token: token.to_owned(),
+            cookies: cookies.to_owned(),
+            saved_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        };
+        let Ok(json) = serde_json::to_string(&session) else {
+            return;
+        };
+        let mut jar = CookieJar::new();
+        jar.private_mut(&self.key)
+            .add(Cookie::new(SESSION_COOKIE, json));
+        let Some(sealed) = jar.get(SESSION_COOKIE) else {
+            return;
+        };
+        let res = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&self.path)
+            .and_then(|mut f| f.write_all(sealed.value().as_bytes()));
+        if let Err(e) = res {
+            warn!("Could not save session to {}: {e}", self.path.display());
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use arcshift::ArcShift;
+use dashmap::DashMap;
+use reqwest::Client;
+use serde::Serialize;
+use tokio::{sync::watch, time::Instant};
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::{breaker::backoff, fetch::unix_now, fetch_task, tenant::Tenant, TimeTableData};
+
+/// First pause before a task that ended is started again, doubled for each further end
+const RESTART_BACKOFF: Duration = Duration::from_secs(60);
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(6 * 60 * 60);
+/// A task that ran this long before it ended starts over with the shortest backoff
+const HEALTHY_RUN: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskState {
+    /// Fetching from upstream
+    Running,
+    /// Waiting for the next tier to become due
+    Idle,
+    /// Ended or crashed, restarts at `restart_at`
+    Failed,
+    /// Stopped by an operator, restarts only when started again
+    Stopped,
+}
+
+#[derive(Clone, Serialize)]
+pub struct TaskStatus {
+    pub element: isize,
+    pub state: TaskState,
+    /// Unix timestamp of the last state change
+    pub since: u64,
+    pub restarts: u32,
+    pub last_error: Option<String>,
+    pub restart_at: Option<u64>,
+}
+
+/// Shared between the supervisor, its fetch task and the admin endpoints
+pub struct TaskHandle {
+    status: Mutex<TaskStatus>,
+    /// Whether the task should run, operators switch it off and on
+    enabled: watch::Sender<bool>,
+}
+
+impl TaskHandle {
+    fn new(element: isize) -> Self {
+        Self {
+            status: Mutex::new(TaskStatus {
+                element,
+                state: TaskState::Idle,
+                since: unix_now(),
+                restarts: 0,
+                last_error: None,
+                restart_at: None,
+            }),
+            enabled: watch::Sender::new(true),
+        }
+    }
+
+    pub fn set(&self, state: TaskState) {
+        let mut status = self.status.lock().unwrap();
+        if status.state != state {
+            status.state = state;
+            status.since = unix_now();
+        }
+        if state != TaskState::Failed {
+            status.restart_at = None;
+        }
+    }
+
+    fn fail(&self, error: String, restart_at: u64) {
+        self.set(TaskState::Failed);
+        let mut status = self.status.lock().unwrap();
+        status.last_error = Some(error);
+        status.restart_at = Some(restart_at);
+    }
+
+    pub fn status(&self) -> TaskStatus {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+/// The fetch tasks of one tenant by element id
+#[derive(Default)]
+pub struct Tasks(DashMap<isize, Arc<TaskHandle>>);
+
+impl Tasks {
+    pub fn status(&self) -> Vec<TaskStatus> {
+        let mut tasks = self.0.iter().map(|t| t.status()).collect::<Vec<_>>();
+        tasks.sort_by_key(|t| t.element);
+        tasks
+    }
+
+    /// Stops the task of `element`, returns false if there is none
+    pub fn stop(&self, element: isize) -> bool {
+        self.0
+            .get(&element)
+            .map(|t| t.enabled.send_replace(false))
+            .is_some()
+    }
+
+    /// Starts a stopped task or restarts a failed one right away
+    pub fn start(&self, element: isize) -> bool {
+        self.0
+            .get(&element)
+            .map(|t| t.enabled.send_replace(true))
+            .is_some()
+    }
+}
+
+/// Keeps the fetch task of `element` alive. It is restarted with exponential backoff
+/// whenever it ends or panics, and can be stopped and started by operators.
+pub async fn supervise(
+    arc: ArcShift<TimeTableData>,
+    client: Client,
+    tenant: Arc<Tenant>,
+    element: isize,
+) {
+    let handle = Arc::new(TaskHandle::new(element));
+    tenant.tasks.0.insert(element, handle.clone());
+    let mut enabled = handle.enabled.subscribe();
+    let mut failures = 0;
+    loop {
+        if !*enabled.borrow_and_update() {
+            handle.set(TaskState::Stopped);
+            info!("Task für {element} angehalten");
+            if enabled.wait_for(|on| *on).await.is_err() {
+                return;
+            }
+            failures = 0;
+        }
+
+        let span = info_span!("ID", school = tenant.id(), key = %element);
+        let task = fetch_task(
+            arc.clone(),
+            client.clone(),
+            tenant.clone(),
+            element,
+            handle.clone(),
+        )
+        .instrument(span);
+        let child = tokio::task::Builder::new()
+            .name(&format!("{} ID {element}", tenant.id()))
+            .spawn(task)
+            .unwrap();
+        let abort = child.abort_handle();
+        let started = Instant::now();
+        let error = tokio::select! {
+            res = child => match res {
+                Ok(reason) => reason,
+                Err(e) if e.is_panic() => "task panicked".to_owned(),
+                Err(e) => e.to_string(),
+            },
+            _ = enabled.wait_for(|on| !*on) => {
+                abort.abort();
+                continue;
+            }
+        };
+
+        failures = if started.elapsed() >= HEALTHY_RUN {
+            1
+        } else {
+            failures + 1
+        };
+        let delay = backoff(RESTART_BACKOFF, failures, MAX_RESTART_BACKOFF);
+        warn!(
+            "Task für {element} beendet ({error}), Neustart in {}s",
+            delay.as_secs()
+        );
+        handle.fail(error, unix_now() + delay.as_secs());
+        // starting the task by hand skips the rest of the backoff
+        enabled.mark_unchanged();
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = enabled.changed() => {}
+        }
+        handle.status.lock().unwrap().restarts += 1;
+    }
+}
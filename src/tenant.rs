@@ -0,0 +1,271 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::ErrorKind,
+    path::Path,
+    sync::{atomic::AtomicU32, Arc},
+};
+
+use arcshift::ArcShift;
+use chrono::{Local, NaiveDate};
+use cookie::Key;
+use dashmap::DashMap;
+use governor::{DefaultDirectRateLimiter, Quota};
+use serde::Serialize;
+
+use crate::{
+    auth::{self, AuthProvider},
+    breaker::{Breaker, BreakerStatus},
+    changes::ChangeLog,
+    config::{ConfigError, SchoolConfig},
+    error::Error,
+    fetch::DayStatus,
+    history::History,
+    jwt::Claims,
+    lockout::{LoginGuard, LoginStatus},
+    metrics::{ErrorStatus, Metrics},
+    policy::{self, Period},
+    sanity::HeldUpdate,
+    schedule::Schedule,
+    session::{Session, SessionManager},
+    snapshot::SnapshotStore,
+    state::SessionStore,
+    supervisor::Tasks,
+    webhook::Webhooks,
+    TimeTableData,
+};
+
+#[derive(Serialize)]
+pub struct TenantStatus {
+    pub school: String,
+    pub login: LoginStatus,
+    pub period: Period,
+    /// Unix timestamp at which the current token expires
+    pub token_expires: Option<u64>,
+    pub errors: ErrorStatus,
+    pub breaker: BreakerStatus,
+    pub elements: Vec<ElementStatus>,
+}
+
+#[derive(Serialize)]
+pub struct ElementStatus {
+    pub element: isize,
+    /// Unix timestamp of the newest successful fetch of any day
+    pub updated_at: Option<u64>,
+    /// Days whose last fetch failed, they keep serving their last good lessons
+    pub stale: BTreeMap<NaiveDate, DayStatus>,
+    /// Updates held back by the sanity gate until the next fetch confirms them
+    pub held: Vec<HeldUpdate>,
+}
+
+/// Everything that belongs to one school. Element ids are only unique inside a tenant,
+/// so each tenant keeps its own data map.
+pub struct Tenant {
+    pub school: SchoolConfig,
+    pub alias: HashMap<String, String>,
+    limiter: DefaultDirectRateLimiter,
+    off_peak_limiter: DefaultDirectRateLimiter,
+    pub auth: Box<dyn AuthProvider>,
+    pub sessions: SessionManager,
+    pub data: DashMap<isize, ArcShift<TimeTableData>>,
+    /// Range size in days that last worked for this school, shrinks when requests
+    /// have to be split and grows back to `fetch.batch_days` after clean cycles
+    pub batch_days: AtomicU32,
+    pub tasks: Tasks,
+    /// Schedules of the fetch tasks by element, a restarted task continues its
+    /// predecessor's instead of fetching every tier right away
+    pub schedules: DashMap<isize, Schedule>,
+    pub metrics: Metrics,
+    pub breaker: Breaker,
+    pub snapshots: SnapshotStore,
+    pub history: Option<Arc<History>>,
+    pub changes: ChangeLog,
+    pub webhooks: Webhooks,
+}
+
+impl Tenant {
+    pub fn new(
+        school: SchoolConfig,
+        state_dir: &Path,
+        key: Key,
+        history: Option<Arc<History>>,
+    ) -> Result<Self, ConfigError> {
+        Ok(Self {
+            alias: load_alias(&school.alias)
+                .map_err(|e| ConfigError::Alias(school.id.clone(), school.alias.clone(), e))?,
+            limiter: DefaultDirectRateLimiter::direct(Quota::per_second(school.rate_limit)),
+            off_peak_limiter: DefaultDirectRateLimiter::direct(Quota::per_second(
+                school.polling.off_peak_rate_limit,
+            )),
+            auth: auth::provider(&school.auth),
+            sessions: SessionManager::new(
+                SessionStore::new(state_dir, &school.id, key.clone()),
+                LoginGuard::new(state_dir, &school, key),
+            ),
+            data: DashMap::new(),
+            batch_days: AtomicU32::new(school.fetch.batch_days),
+            tasks: Tasks::default(),
+            schedules: DashMap::new(),
+            metrics: Metrics::default(),
+            breaker: Breaker::new(school.breaker.clone()),
+            snapshots: SnapshotStore::new(state_dir, &school.id),
+            history,
+            changes: ChangeLog::default(),
+            webhooks: Webhooks::new(&school, state_dir),
+            school,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.school.id
+    }
+
+    pub async fn session(&self) -> Result<Session, Error> {
+        self.sessions
+            .get(&self.school, self.auth.as_ref())
+            .await
+            .inspect_err(|e| self.metrics.error(e))
+    }
+
+    /// Keeps the session of this school fresh, runs forever
+    pub async fn keep_session_fresh(&self) {
+        self.sessions
+            .keep_fresh(&self.school, self.auth.as_ref())
+            .await
+    }
+
+    pub fn status(&self) -> TenantStatus {
+        TenantStatus {
+            school: self.id().to_owned(),
+            login: self.sessions.login_status(),
+            period: self.period(),
+            token_expires: self.claims().map(|c| c.exp),
+            errors: self.metrics.status(),
+            breaker: self.breaker.status(),
+            elements: self.element_status(),
+        }
+    }
+
+    fn element_status(&self) -> Vec<ElementStatus> {
+        let mut elements = self
+            .data
+            .iter()
+            .map(|item| {
+                let data = item.value().shared_get();
+                let days = &data.days;
+                ElementStatus {
+                    element: *item.key(),
+                    updated_at: days.values().filter_map(|d| d.fetched_at).max(),
+                    stale: days
+                        .iter()
+                        .filter(|(_, d)| d.failing_since.is_some())
+                        .map(|(day, d)| (*day, d.clone()))
+                        .collect(),
+                    held: data.held.values().cloned().collect(),
+                }
+            })
+            .collect::<Vec<_>>();
+        elements.sort_by_key(|e| e.element);
+        elements
+    }
+
+    /// Claims of the service account's current token
+    pub fn claims(&self) -> Option<Claims> {
+        self.sessions.claims()
+    }
+
+    /// Current polling period, holidays are also detected from the default grade
+    pub fn period(&self) -> Period {
+        let now = Local::now().naive_local();
+        let no_lessons = self.data.get(&self.default_key()).is_some_and(|data| {
+            data.shared_get()
+                .entries
+                .get(&now.date())
+                .is_some_and(Vec::is_empty)
+        });
+        policy::period(&self.school.polling, now, no_lessons)
+    }
+
+    /// Limiter for upstream requests, stricter at night and on free days
+    pub fn limiter(&self) -> &DefaultDirectRateLimiter {
+        if self.period().is_off_peak() {
+            &self.off_peak_limiter
+        } else {
+            &self.limiter
+        }
+    }
+
+    pub fn default_key(&self) -> isize {
+        -self.school.default_grade()
+    }
+}
+
+/// Reads the alias file at `path`, an empty one is created if it does not exist
+fn load_alias(path: &str) -> std::io::Result<HashMap<String, String>> {
+    let buf = match std::fs::read_to_string(path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            File::create_new(path)?;
+            String::new()
+        }
+        res => res?,
+    };
+
+    Ok(buf
+        .split("\n")
+        .filter_map(|el| {
+            el.find(";")
+                .map(|i| el.split_at(i))
+                .map(|f| (f.0.to_owned(), f.1.strip_prefix(";").unwrap().to_owned()))
+        })
+        .collect::<HashMap<String, String>>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn creates_missing_alias_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("alias");
+        let alias = load_alias(path.to_str().unwrap()).unwrap();
+        assert!(alias.is_empty());
+        assert!(path.exists());
+
+        std::fs::write(&path, "MA1;Mathe\nlA1;Raum 1\n").unwrap();
+        let alias = load_alias(path.to_str().unwrap()).unwrap();
+        assert_eq!(alias["MA1"], "Mathe");
+        assert_eq!(alias["lA1"], "Raum 1");
+    }
+
+    #[test]
+    fn unusable_alias_file_is_a_config_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing/alias");
+        let invalid = dir.path().join("alias");
+        std::fs::write(&invalid, [0xff, 0xfe, b';', b'x']).unwrap();
+        for path in [missing, invalid] {
+            let path = path.display().to_string();
+            let school = crate::testutil::school(
+                "http://127.0.0.1:1",
+                &format!(
+                    r#"
+                    alias = "{path}"
+                    [auth]
+                    provider = "webuntis"
+                    [credentials]
+                    username = {{ value = "anna" }}
+                    password = {{ value = "geheim" }}
+                    "#
+                ),
+            );
+            let err = Tenant::new(school, dir.path(), Key::generate(), None)
+                .err()
+                .unwrap();
+            assert!(
+                matches!(&err, ConfigError::Alias(school, p, _) if school == "test" && *p == path)
+            );
+            assert!(err.to_string().contains(&path));
+        }
+    }
+}
@@ -0,0 +1,184 @@
+//! Helpers shared by the unit tests
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use bytes::Bytes;
+use cookie::Key;
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    body::Incoming,
+    header::{COOKIE, HOST, LOCATION, SET_COOKIE},
+    server::conn::http1,
+    service::service_fn,
+    Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use reqwest::Url;
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+
+use crate::{config::SchoolConfig, secret::Secret, tenant::Tenant};
+
+/// Serves `handle` on a free local port for the rest of the test and returns the address.
+/// Request bodies are collected before `handle` sees them.
+pub async fn serve<F>(handle: F) -> SocketAddr
+where
+    F: Fn(Request<Bytes>) -> Response<String> + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handle = Arc::new(handle);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let handle = handle.clone();
+            let service = service_fn(move |req: Request<Incoming>| {
+                let handle = handle.clone();
+                async move {
+                    let (parts, body) = req.into_parts();
+                    let body = body
+                        .collect()
+                        .await
+                        .map(|b| b.to_bytes())
+                        .unwrap_or_default();
+                    let res = handle(Request::from_parts(parts, body));
+                    Ok::<_, Infallible>(res.map(|b| Full::new(Bytes::from(b))))
+                }
+            });
+            tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+        }
+    });
+    addr
+}
+
+pub fn redirect(location: &str) -> Response<String> {
+    Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, location)
+        .body(String::new())
+        .unwrap()
+}
+
+pub fn status(status: StatusCode) -> Response<String> {
+    Response::builder()
+        .status(status)
+        .body(String::new())
+        .unwrap()
+}
+
+/// Port the client connected to, taken from the `Host` header
+pub fn port(req: &Request<Bytes>) -> u16 {
+    req.headers()
+        .get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.rsplit(':').next())
+        .and_then(|p| p.parse().ok())
+        .unwrap()
+}
+
+/// Fields of an `application/x-www-form-urlencoded` body
+pub fn form(req: &Request<Bytes>) -> HashMap<String, String> {
+    let mut url = Url::parse("http://form/").unwrap();
+    url.set_query(Some(&String::from_utf8_lossy(req.body())));
+    url.query_pairs().into_owned().collect()
+}
+
+pub fn cookie<'a>(req: &'a Request<Bytes>, name: &str) -> Option<&'a str> {
+    req.headers()
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|c| c.trim().strip_prefix(name)?.strip_prefix('='))
+}
+
+pub fn secret(value: &str) -> Secret {
+    serde_json::from_value(serde_json::json!(value)).unwrap()
+}
+
+/// Unsigned JWT that expires in an hour
+pub fn token() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let claims = serde_json::json!({"exp": now + 3600, "iat": now, "person_id": 7});
+    format!(
+        "eyJhbGciOiJub25lIn0.{}.",
+        URL_SAFE_NO_PAD.encode(claims.to_string())
+    )
+}
+
+/// School served by WebUntis at `untis_url`, `extra` is appended to its config
+pub fn school(untis_url: &str, extra: &str) -> SchoolConfig {
+    toml::from_str(&format!(
+        r#"
+        id = "test"
+        untis_url = "{untis_url}"
+        school_name = "_dGVzdA=="
+        tenant_id = "1"
+        grades = [1]
+        location = "Schule"
+        negative_offset = 7
+        positive_offset = 14
+        {extra}
+        "#
+    ))
+    .unwrap()
+}
+
+/// Answers the WebUntis side of a login: the OIDC callback and any password start a
+/// session and `/api/token/new` hands out a token for it. `None` for every other request.
+pub fn webuntis(req: &Request<Bytes>) -> Option<Response<String>> {
+    match req.uri().path() {
+        "/WebUntis/j_spring_security_check" => Some(
+            Response::builder()
+                .header(SET_COOKIE, "JSESSIONID=untis; Path=/WebUntis")
+                .body(r#"{"state":"SUCCESS"}"#.to_owned())
+                .unwrap(),
+        ),
+        "/WebUntis/oidc/callback" => Some(
+            Response::builder()
+                .header(SET_COOKIE, "JSESSIONID=untis; Path=/WebUntis")
+                .body(String::new())
+                .unwrap(),
+        ),
+        "/WebUntis/api/token/new" if cookie(req, "JSESSIONID") == Some("untis") => {
+            Some(Response::new(token()))
+        }
+        "/WebUntis/api/token/new" => Some(status(StatusCode::UNAUTHORIZED)),
+        _ => None,
+    }
+}
+
+/// Tenant of a school served by WebUntis at `untis_url` that logs in with any password
+/// and is not slowed down by rate limits. Its state lives in the returned directory.
+pub fn tenant(untis_url: &str, extra: &str) -> (Tenant, TempDir) {
+    let dir = tempfile::tempdir().unwrap();
+    let school = school(
+        untis_url,
+        &format!(
+            r#"
+            alias = "{}"
+            rate_limit = 1000
+            {extra}
+            [auth]
+            provider = "webuntis"
+            [credentials]
+            username = {{ value = "anna" }}
+            password = {{ value = "geheim" }}
+            [polling]
+            off_peak_rate_limit = 1000
+            "#,
+            dir.path().join("alias").display()
+        ),
+    );
+    let tenant = Tenant::new(school, dir.path(), Key::generate(), None).unwrap();
+    (tenant, dir)
+}
@@ -0,0 +1,343 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use hmac::{Hmac, KeyInit, Mac};
+use reqwest::{header::CONTENT_TYPE, Client, StatusCode};
+use serde::Serialize;
+use sha2::Sha256;
+use tracing::{debug, error, warn};
+
+use crate::{
+    breaker::{backoff, jitter},
+    changes::Change,
+    config::{in_courses, SchoolConfig, WebhookConfig},
+    fetch::unix_now,
+    secret::Secret,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Pause before the second attempt, doubled for each further one
+const RETRY_DELAY: Duration = Duration::from_secs(10);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    school: &'a str,
+    changes: Vec<&'a Change>,
+}
+
+#[derive(Serialize)]
+struct DeadLetter<'a> {
+    /// Unix timestamp of the last attempt
+    at: u64,
+    url: &'a str,
+    attempts: u32,
+    error: &'a str,
+    payload: serde_json::Value,
+}
+
+struct Hook {
+    config: WebhookConfig,
+    secret: Option<Secret>,
+}
+
+/// Webhooks of a tenant. Each delivery runs in its own task and is retried with
+/// backoff, deliveries that keep failing are appended to the dead-letter log.
+pub struct Webhooks {
+    school: String,
+    hooks: Vec<Arc<Hook>>,
+    client: Client,
+    dead_letters: Arc<PathBuf>,
+}
+
+impl Webhooks {
+    pub fn new(school: &SchoolConfig, state_dir: &Path) -> Self {
+        let hooks = school
+            .webhooks
+            .iter()
+            .filter_map(|config| {
+                let secret = match config.secret.as_ref().map(|s| s.resolve()) {
+                    Some(Err(e)) => {
+                        error!(
+                            "Secret of webhook {} can not be read, it is disabled: {e}",
+                            config.url
+                        );
+                        return None;
+                    }
+                    secret => secret.and_then(Result::ok),
+                };
+                Some(Arc::new(Hook {
+                    config: config.clone(),
+                    secret,
+                }))
+            })
+            .collect();
+        Self {
+            school: school.id.clone(),
+            hooks,
+            client: Client::builder()
+                .timeout(TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+            dead_letters: Arc::new(state_dir.join(format!("{}.webhooks.dead", school.id))),
+        }
+    }
+
+    /// Sends the matching `changes` to every webhook, in the background
+    pub fn notify(&self, changes: &[Change]) {
+        for hook in &self.hooks {
+            let config = &hook.config;
+            let changes = changes
+                .iter()
+                .filter(|c| config.events.iter().any(|e| e == c.kind.name()))
+                .filter(|c| in_courses(&config.courses, c.subject.as_deref()))
+                .collect::<Vec<_>>();
+            if changes.is_empty() {
+                continue;
+            }
+            let payload = Payload {
+                school: &self.school,
+                changes,
+            };
+            let Ok(body) = serde_json::to_string(&payload) else {
+                continue;
+            };
+            tokio::spawn(deliver(
+                self.client.clone(),
+                hook.clone(),
+                body,
+                self.dead_letters.clone(),
+            ));
+        }
+    }
+}
+
+async fn deliver(client: Client, hook: Arc<Hook>, body: String, dead_letters: Arc<PathBuf>) {
+    let url = &hook.config.url;
+    let mut attempt = 0;
+    let error = loop {
+        attempt += 1;
+        let timestamp = unix_now().to_string();
+        let mut req = client
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .header("X-Untis-Timestamp", &timestamp);
+        if let Some(secret) = &hook.secret {
+            req = req.header("X-Untis-Signature", sign(secret, &timestamp, &body));
+        }
+        let error = match req.body(body.clone()).send().await {
+            Ok(res) if res.status().is_success() => {
+                debug!("Webhook {url} delivered");
+                return;
+            }
+            Ok(res) => {
+                let status = res.status();
+                let retry = status.is_server_error()
+                    || matches!(
+                        status,
+                        StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS
+                    );
+                let error = format!("receiver answered {status}");
+                if !retry {
+                    break error;
+                }
+                error
+            }
+            Err(e) => e.to_string(),
+        };
+        if attempt >= hook.config.max_attempts {
+            break error;
+        }
+        let delay = backoff(RETRY_DELAY, attempt, MAX_RETRY_DELAY);
+        warn!(
+            "Webhook {url} failed ({error}), attempt {}/{} in {}s",
+            attempt + 1,
+            hook.config.max_attempts,
+            delay.as_secs()
+        );
+        tokio::time::sleep(jitter(delay)).await;
+    };
+    error!(
+        "Webhook {url} failed after {attempt} attempts, moving it to the dead-letter log: {error}"
+    );
+    let letter = DeadLetter {
+        at: unix_now(),
+        url,
+        attempts: attempt,
+        error: &error,
+        payload: serde_json::from_str(&body).unwrap_or_default(),
+    };
+    let Ok(mut line) = serde_json::to_string(&letter) else {
+        return;
+    };
+    line.push('\n');
+    let res = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(dead_letters.as_path())
+        .and_then(|mut f| f.write_all(line.as_bytes()));
+    if let Err(e) = res {
+        error!("Could not write to {}: {e}", dead_letters.display());
+    }
+}
+
+/// `sha256=` and the hex HMAC of `<timestamp>.<body>`
+fn sign(secret: &Secret, timestamp: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.expose().as_bytes()).expect("any key length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    let hex = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+    format!("sha256={hex}")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::fs::PermissionsExt, sync::Mutex};
+
+    use hyper::Request;
+
+    use super::*;
+    use crate::testutil::{secret, serve, status};
+
+    const SECRET: &str = "hooksecret";
+
+    /// What the receiver saw of one attempt
+    struct Attempt {
+        signed: bool,
+        body: String,
+    }
+
+    /// Receiver that answers the attempts with `statuses` in turn, the last one repeats
+    async fn receiver(statuses: Vec<u16>) -> (String, Arc<Mutex<Vec<Attempt>>>) {
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let seen = attempts.clone();
+        let addr = serve(move |req: Request<bytes::Bytes>| {
+            let header = |name| {
+                req.headers()
+                    .get(name)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or_default()
+                    .to_owned()
+            };
+            // checked independently of `sign`
+            let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+            mac.update(format!("{}.", header("X-Untis-Timestamp")).as_bytes());
+            mac.update(req.body());
+            let signed = header("X-Untis-Signature")
+                .strip_prefix("sha256=")
+                .and_then(|hex| {
+                    (0..hex.len())
+                        .step_by(2)
+                        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+                        .collect::<Option<Vec<_>>>()
+                })
+                .is_some_and(|tag| mac.verify_slice(&tag).is_ok());
+            let mut attempts = seen.lock().unwrap();
+            attempts.push(Attempt {
+                signed,
+                body: String::from_utf8_lossy(req.body()).into_owned(),
+            });
+            let code = statuses[(attempts.len() - 1).min(statuses.len() - 1)];
+            status(StatusCode::from_u16(code).unwrap())
+        })
+        .await;
+        (format!("http://127.0.0.1:{}/hook", addr.port()), attempts)
+    }
+
+    fn hook(url: &str) -> Arc<Hook> {
+        Arc::new(Hook {
+            config: WebhookConfig {
+                url: url.to_owned(),
+                courses: Vec::new(),
+                events: vec!["cancelled".to_owned()],
+                secret: None,
+                max_attempts: 3,
+            },
+            secret: Some(secret(SECRET)),
+        })
+    }
+
+    const BODY: &str = r#"{"school":"gam","changes":[]}"#;
+
+    fn dead_letters(path: &Path) -> Vec<serde_json::Value> {
+        std::fs::read_to_string(path)
+            .unwrap_or_default()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_server_errors_until_delivered() {
+        let (url, attempts) = receiver(vec![503, 500, 204]).await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = Arc::new(dir.path().join("gam.webhooks.dead"));
+        deliver(Client::new(), hook(&url), BODY.to_owned(), path.clone()).await;
+
+        let attempts = attempts.lock().unwrap();
+        assert_eq!(attempts.len(), 3);
+        assert!(attempts.iter().all(|a| a.signed && a.body == BODY));
+        assert!(dead_letters(&path).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dead_letter_after_max_attempts() {
+        let (url, attempts) = receiver(vec![500]).await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = Arc::new(dir.path().join("gam.webhooks.dead"));
+        deliver(Client::new(), hook(&url), BODY.to_owned(), path.clone()).await;
+
+        assert_eq!(attempts.lock().unwrap().len(), 3);
+        let letters = dead_letters(&path);
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0]["url"], url.as_str());
+        assert_eq!(letters[0]["attempts"], 3);
+        assert_eq!(
+            letters[0]["error"],
+            "receiver answered 500 Internal Server Error"
+        );
+        assert_eq!(letters[0]["payload"]["school"], "gam");
+        assert_eq!(
+            std::fs::metadata(path.as_path())
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777,
+            0o600
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn client_errors_are_not_retried() {
+        let (url, attempts) = receiver(vec![410]).await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = Arc::new(dir.path().join("gam.webhooks.dead"));
+        deliver(Client::new(), hook(&url), BODY.to_owned(), path.clone()).await;
+
+        assert_eq!(attempts.lock().unwrap().len(), 1);
+        assert_eq!(dead_letters(&path)[0]["attempts"], 1);
+    }
+
+    #[test]
+    fn signature() {
+        assert_eq!(
+            sign(&secret("key"), "1700000000", "{}"),
+            // printf '1700000000.{}' | openssl dgst -sha256 -hmac key
+            "sha256=9d713ed406bb7076d4123f0dc2c39d2df5c654ed4b0cd56b52c8b4c940bd63ae"
+        );
+    }
+}